@@ -0,0 +1,222 @@
+use crate::*;
+
+// the book an amended offer rests on, offer ids are only unique within a book
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OfferSide {
+  Lending,
+  Borrowing
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OfferTerms {
+  pub apr: U128,
+  pub loan_duration: U128,
+  pub expires_at: Option<U128>
+}
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // changes the value and terms of a resting offer while its escrowed funds or NFTs stay in the
+  // contract. The offer goes to the back of its new price level and is matched again, returns
+  // whether it's still resting in the book
+  #[payable]
+  pub fn amend_offer(&mut self, nft_collection_id: NftCollection, offer_id: String, side: OfferSide, new_value: U128, new_terms: OfferTerms) -> bool {
+    assert!(new_value.0 > 0, "The offer value must be positive");
+    match side {
+      OfferSide::Lending => self.amend_lending_offer(nft_collection_id, offer_id, new_value.0, new_terms),
+      OfferSide::Borrowing => self.amend_borrowing_offer(nft_collection_id, offer_id, new_value.0, new_terms)
+    }
+  }
+}
+
+impl LendingNftCollateral {
+
+  // a higher value escrows the difference from the attached deposit or the owner's balance, a
+  // lower one credits it back to the balance
+  fn amend_lending_offer(&mut self, nft_collection_id: NftCollection, offer_id: String, new_value: u128, new_terms: OfferTerms) -> bool {
+    let lending_offer = self.remove_lending_offer(nft_collection_id.clone(), offer_id);
+    let owner_id = env::predecessor_account_id();
+    assert!(owner_id == lending_offer.owner_id, "You are not the owner of this offer");
+
+    let increase = new_value.saturating_sub(lending_offer.value);
+    match &lending_offer.currency {
+      None => self.lock_funds(owner_id.clone(), increase),
+      Some(_) => {
+        // the increase comes from the token balance, NEAR sent along is kept in the NEAR balance
+        if env::attached_deposit() > 0 {
+          self.credit_balance(owner_id.clone(), env::attached_deposit());
+        }
+        let current_value = self.get_funds(owner_id.clone(), &lending_offer.currency);
+        assert!(increase <= current_value, "You don't have enough credit for this transaction");
+        self.set_funds(owner_id.clone(), &lending_offer.currency, current_value - increase);
+      }
+    }
+    if lending_offer.value > new_value {
+      self.credit_funds(owner_id, &lending_offer.currency, lending_offer.value - new_value);
+    }
+
+    let amended_offer = Offer{value: new_value, ..lending_offer.with_terms(&new_terms)};
+    self.assert_valid_offer_terms(&nft_collection_id, &amended_offer);
+    let remaining_offer = self.fill_lending_offer(nft_collection_id.clone(), amended_offer);
+    if remaining_offer.value == 0 {
+      return false;
+    }
+    self.insert_lending_offer(nft_collection_id, remaining_offer);
+    true
+  }
+
+  // the NFTs stay in escrow, nothing is transferred unless the new price crosses
+  fn amend_borrowing_offer(&mut self, nft_collection_id: NftCollection, offer_id: String, new_value: u128, new_terms: OfferTerms) -> bool {
+    let borrowing_offer = self.remove_borrowing_offer(nft_collection_id.clone(), offer_id);
+    let owner_id = env::predecessor_account_id();
+    assert!(owner_id == borrowing_offer.owner_id, "You are not the owner of this offer");
+    if env::attached_deposit() > 0 {
+      self.credit_balance(owner_id, env::attached_deposit());
+    }
+
+    let amended_offer = Offer{value: new_value, ..borrowing_offer.with_terms(&new_terms)};
+    self.assert_valid_borrowing_offer(&nft_collection_id, &amended_offer);
+    if !amended_offer.bundled_collateral.is_empty() {
+      assert!(new_value <= self.calculate_bundle_loan_limit(&amended_offer.collateral(&nft_collection_id)), "The value requested is higher than the bundle's limit");
+    }
+    if self.fill_borrowing_offer(nft_collection_id.clone(), &amended_offer) {
+      return false;
+    }
+    self.insert_borrowing_offer(nft_collection_id, amended_offer);
+    true
+  }
+}
+
+impl Offer {
+
+  fn with_terms(self, terms: &OfferTerms) -> Offer {
+    let offer = Offer{apr: terms.apr.0, loan_duration: terms.loan_duration.0, expires_at: terms.expires_at.map(|timestamp| timestamp.0), ..self};
+    assert!(!offer.is_expired(), "The offer would already be expired");
+    offer
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::MockedBlockchain;
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  fn setup_contract() -> LendingNftCollateral {
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+    let collection_params = CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()};
+    contract.collection_params.insert(&"nft_collection_test".to_string(), &collection_params);
+    contract
+  }
+
+  fn terms(apr: u128) -> OfferTerms {
+    OfferTerms{apr: U128(apr), loan_duration: U128(604800), expires_at: None}
+  }
+
+  #[test]
+  fn test_amend_lending_offer() {
+    let mut context = get_context(accounts(4));
+    testing_env!(context.build());
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    let lending_offer = Offer{offer_id: "0".to_string(), owner_id: accounts(4).into(), value: 10, apr: 1000, loan_duration: 604800, ..Default::default()};
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer);
+    contract.insert_lending_offer(nft_collection_id.clone(), Offer{offer_id: "1".to_string(), value: 15, ..Default::default()});
+
+    // the increase is escrowed from the deposit and the offer moves ahead of the other one
+    testing_env!(context
+      .attached_deposit(10)
+      .build());
+    assert!(contract.amend_offer(nft_collection_id.clone(), "0".to_string(), OfferSide::Lending, U128(20), terms(800)));
+    let best_offer = contract.get_best_lending_offer(nft_collection_id.clone()).unwrap();
+    assert_eq!(best_offer.offer_id, "0".to_string());
+    assert_eq!(best_offer.value, 20);
+    assert_eq!(best_offer.apr, 800);
+    assert_eq!(contract.get_balance_value(accounts(4).into()), 0);
+
+    // lowering it credits the difference back
+    testing_env!(context
+      .attached_deposit(0)
+      .build());
+    assert!(contract.amend_offer(nft_collection_id.clone(), "0".to_string(), OfferSide::Lending, U128(5), terms(800)));
+    assert_eq!(contract.get_balance_value(accounts(4).into()), 15);
+    assert_eq!(contract.get_best_lending_offer(nft_collection_id).unwrap().offer_id, "1".to_string());
+  }
+
+  #[test]
+  fn test_amend_borrowing_offer_rematches() {
+    let context = get_context(accounts(4));
+    testing_env!(context.build());
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    let borrowing_offer = Offer{offer_id: "0".to_string(), owner_id: accounts(4).into(), value: 10, token_id: Some("token_id".to_string()), apr: 500, loan_duration: 604800, ..Default::default()};
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer);
+    let lending_offer = Offer{offer_id: "0".to_string(), owner_id: accounts(5).into(), value: 30, apr: 800, loan_duration: 604800, ..Default::default()};
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer);
+
+    // accepting a higher rate crosses the resting lending offer
+    assert!(!contract.amend_offer(nft_collection_id.clone(), "0".to_string(), OfferSide::Borrowing, U128(10), terms(900)));
+    assert!(contract.get_best_borrowing_offer(nft_collection_id.clone()).is_none());
+    assert_eq!(contract.get_best_lending_offer(nft_collection_id).unwrap().value, 20);
+  }
+
+  #[test]
+  fn test_amend_ft_lending_offer_keeps_deposit() {
+    let mut context = get_context(accounts(4));
+    testing_env!(context.build());
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    let lending_offer = Offer{offer_id: "0".to_string(), owner_id: accounts(4).into(), value: 10, apr: 1000, loan_duration: 604800, currency: Some(accounts(5).into()), ..Default::default()};
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer);
+    testing_env!(context
+      .attached_deposit(7)
+      .build());
+    assert!(contract.amend_offer(nft_collection_id, "0".to_string(), OfferSide::Lending, U128(5), terms(800)));
+    assert_eq!(contract.get_balance_value(accounts(4).into()), 7);
+    assert_eq!(contract.get_ft_balance_value(accounts(4).into(), accounts(5).into()).0, 5);
+  }
+
+  #[test]
+  #[should_panic(expected = "The value requested is higher than the bundle's limit")]
+  fn test_amend_bundle_borrowing_offer_over_limit() {
+    let context = get_context(accounts(4));
+    testing_env!(context.build());
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    let bundled_collateral = vec![Collateral{nft_collection_id: nft_collection_id.clone(), token_id: "token_id2".to_string()}];
+    let borrowing_offer = Offer{offer_id: "0".to_string(), owner_id: accounts(4).into(), value: 150, token_id: Some("token_id1".to_string()), apr: 500, loan_duration: 604800, bundled_collateral, ..Default::default()};
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer);
+    contract.amend_offer(nft_collection_id, "0".to_string(), OfferSide::Borrowing, U128(250), terms(500));
+  }
+
+  #[test]
+  #[should_panic(expected = "You are not the owner of this offer")]
+  fn test_amend_offer_not_owner() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    let borrowing_offer = Offer{offer_id: "0".to_string(), owner_id: accounts(4).into(), value: 10, token_id: Some("token_id".to_string()), ..Default::default()};
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer);
+    contract.amend_offer(nft_collection_id, "0".to_string(), OfferSide::Borrowing, U128(20), terms(500));
+  }
+}
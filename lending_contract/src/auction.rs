@@ -0,0 +1,283 @@
+use crate::*;
+
+// how long the price takes to go from the starting to the ending price, in seconds
+const AUCTION_DURATION: u128 = 259_200;
+// starting and ending price over the defaulted debt, in basis points
+const AUCTION_START_PRICE_RATE: u128 = 20_000;
+const AUCTION_END_PRICE_RATE: u128 = 5_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Auction {
+  pub note_owner_id: AccountId,
+  pub borrower_id: AccountId,
+  // principal plus interest (and late fee) owed when the loan defaulted
+  pub debt: u128,
+  pub start_price: u128,
+  pub end_price: u128,
+  pub start_time: u128,
+  pub warranty_collection: AccountId,
+  pub warranty_token_id: String,
+  pub bundled_collateral: Vec<Collateral>,
+  // bids are paid in the loan's currency
+  pub currency: Option<AccountId>
+}
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // called by the note contract when the note holder chooses to auction the collateral
+  // instead of claiming it, only possible once the grace period is over
+  pub fn liquidate_loan(&mut self, token_id: TokenId, note_owner_id: AccountId) -> Promise {
+    assert!(env::predecessor_account_id() == self.note_address, "Only note contract can call this function");
+    let loan = self.loans.get(&token_id).expect("Loan not found");
+    self.assert_active_loan(&loan);
+    assert!(self.get_grace_period_end(&loan) < env::block_timestamp() as u128, "This loan's grace period hasn't ended yet");
+    assert!(self.auctions.get(&token_id).is_none(), "This loan is already being liquidated");
+
+    ext_nft_contract::nft_token(
+      token_id.clone(),
+      &self.receipt_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    ).then(ext_self::resolve_liquidation(
+      token_id,
+      note_owner_id,
+      &env::current_account_id(),
+      NO_DEPOSIT,
+      CALLBACK_GAS
+    ))
+  }
+
+  // starts the auction, the receipt holder is recorded as the borrower so they get any surplus
+  #[private]
+  pub fn resolve_liquidation(&mut self, token_id: TokenId, note_owner_id: AccountId) -> bool {
+    let mut loan = self.loans.get(&token_id).unwrap();
+    // the loan may have been claimed while the receipt owner was being checked
+    if loan.status != LoanStatus::Active {
+      return false;
+    }
+    let borrower_id = self.get_token_owner_from_promise(0).unwrap_or_else(|| note_owner_id.clone());
+    let debt = self.calculate_outstanding_balance(&loan);
+    let auction = Auction {
+      note_owner_id,
+      borrower_id,
+      debt,
+      start_price: debt * AUCTION_START_PRICE_RATE / BASIS_POINTS,
+      end_price: debt * AUCTION_END_PRICE_RATE / BASIS_POINTS,
+      start_time: env::block_timestamp() as u128,
+      warranty_collection: loan.warranty_collection.clone(),
+      warranty_token_id: loan.warranty_token_id.clone(),
+      bundled_collateral: loan.bundled_collateral.clone(),
+      currency: loan.currency.clone()
+    };
+    self.auctions.insert(&token_id, &auction);
+    self.set_loan_status(&token_id, &mut loan, LoanStatus::Liquidating);
+    self.burn_loan_tokens(token_id);
+    true
+  }
+
+  // buys the collateral at the current price, paid with the attached deposit and the bidder's balance
+  #[payable]
+  pub fn bid_on_auction(&mut self, token_id: TokenId) -> Promise {
+    let auction = self.auctions.get(&token_id).expect("Auction not found");
+    assert!(auction.currency.is_none(), "This auction must be paid through ft_transfer_call");
+    let price = self.calculate_auction_price(&auction);
+    let bidder_id = env::predecessor_account_id();
+    self.lock_funds(bidder_id.clone(), price);
+    self.settle_auction(token_id, auction, bidder_id, price)
+  }
+
+  // nobody bid by the end of the auction, the note holder takes the collateral instead
+  pub fn claim_unsold_collateral(&mut self, token_id: TokenId) -> Promise {
+    let auction = self.auctions.get(&token_id).expect("Auction not found");
+    assert!(env::predecessor_account_id() == auction.note_owner_id, "Only the note holder can claim the collateral");
+    let elapsed_seconds = (env::block_timestamp() as u128).saturating_sub(auction.start_time) / NANOSECONDS_PER_SECOND;
+    assert!(elapsed_seconds >= AUCTION_DURATION, "This auction hasn't ended yet");
+    let note_owner_id = auction.note_owner_id.clone();
+    self.settle_auction(token_id, auction, note_owner_id, 0)
+  }
+
+  pub fn get_auction(&self, token_id: TokenId) -> Option<Auction> {
+    self.auctions.get(&token_id)
+  }
+
+  pub fn get_auction_price(&self, token_id: TokenId) -> U128 {
+    let auction = self.auctions.get(&token_id).expect("Auction not found");
+    U128(self.calculate_auction_price(&auction))
+  }
+}
+
+impl LendingNftCollateral {
+
+  // the price is already held by the contract, the note holder gets up to the debt
+  // and whatever is left goes to the borrower
+  pub fn settle_auction(&mut self, token_id: TokenId, auction: Auction, bidder_id: AccountId, price: u128) -> Promise {
+    self.auctions.remove(&token_id);
+    let mut loan = self.loans.get(&token_id).unwrap();
+    self.set_loan_status(&token_id, &mut loan, LoanStatus::Defaulted);
+    let note_owner_value = std::cmp::min(price, auction.debt);
+    if note_owner_value > 0 {
+      self.pay_out(auction.note_owner_id, &auction.currency, note_owner_value);
+    }
+    if price > note_owner_value {
+      self.pay_out(auction.borrower_id, &auction.currency, price - note_owner_value);
+    }
+
+    let mut collateral = vec![Collateral{nft_collection_id: auction.warranty_collection, token_id: auction.warranty_token_id}];
+    collateral.extend(auction.bundled_collateral);
+    self.transfer_collateral(bidder_id, collateral)
+  }
+
+  // price goes down linearly and stays at the ending price once the auction duration is over
+  pub fn calculate_auction_price(&self, auction: &Auction) -> u128 {
+    let elapsed_seconds = (env::block_timestamp() as u128).saturating_sub(auction.start_time) / NANOSECONDS_PER_SECOND;
+    if elapsed_seconds >= AUCTION_DURATION {
+      return auction.end_price;
+    }
+    let price_drop = (auction.start_price - auction.end_price) * elapsed_seconds / AUCTION_DURATION;
+    auction.start_price - price_drop
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::{MockedBlockchain, RuntimeFeesConfig, VMConfig};
+
+  use super::*;
+  use crate::loan::tests::sample_loan;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+
+  fn sample_auction() -> Auction {
+    Auction {
+      note_owner_id: accounts(4).into(),
+      borrower_id: accounts(5).into(),
+      debt: 1000,
+      start_price: 2000,
+      end_price: 500,
+      start_time: 0,
+      warranty_collection: "nft_collection_test".to_string(),
+      warranty_token_id: "token_id".to_string(),
+      bundled_collateral: Vec::new(),
+      currency: None
+    }
+  }
+
+  #[test]
+  fn test_resolve_liquidation() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let token = Token{token_id: "0".to_string(), owner_id: accounts(5).into(), metadata: None, approved_account_ids: None, royalty: None};
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Successful(serde_json::to_vec(&Some(token)).unwrap())]
+    );
+    contract.loans.insert(&"0".to_string(), &Loan{status: LoanStatus::Active, ..sample_loan(1000, 0)});
+    let success = contract.resolve_liquidation("0".to_string(), accounts(4).into());
+    assert!(success);
+    assert_eq!(contract.loans.get(&"0".to_string()).unwrap().status, LoanStatus::Liquidating);
+    let auction = contract.get_auction("0".to_string()).unwrap();
+    assert_eq!(auction.borrower_id, accounts(5).to_string());
+    assert_eq!(auction.start_price, 2000);
+    assert_eq!(auction.end_price, 500);
+  }
+
+  #[test]
+  fn test_calculate_auction_price() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let auction = sample_auction();
+    assert_eq!(contract.calculate_auction_price(&auction), 2000);
+    testing_env!(context
+      .block_timestamp((AUCTION_DURATION / 2 * NANOSECONDS_PER_SECOND) as u64)
+      .build());
+    assert_eq!(contract.calculate_auction_price(&auction), 1250);
+    testing_env!(context
+      .block_timestamp((AUCTION_DURATION * 2 * NANOSECONDS_PER_SECOND) as u64)
+      .build());
+    assert_eq!(contract.calculate_auction_price(&auction), 500);
+  }
+
+  #[test]
+  fn test_bid_on_auction() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // the attached deposit above the price goes to the bidder's balance
+    testing_env!(context
+      .block_timestamp((AUCTION_DURATION / 2 * NANOSECONDS_PER_SECOND) as u64)
+      .attached_deposit(1300)
+      .predecessor_account_id(accounts(3))
+      .build());
+    contract.auctions.insert(&"0".to_string(), &sample_auction());
+    contract.loans.insert(&"0".to_string(), &Loan{status: LoanStatus::Liquidating, ..sample_loan(1000, 0)});
+    contract.bid_on_auction("0".to_string());
+    assert!(contract.get_auction("0".to_string()).is_none());
+    assert_eq!(contract.loans.get(&"0".to_string()).unwrap().status, LoanStatus::Defaulted);
+    assert_eq!(contract.balances.get(&accounts(3).into()).unwrap(), 50);
+  }
+
+  #[test]
+  fn test_claim_unsold_collateral() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .block_timestamp((AUCTION_DURATION * NANOSECONDS_PER_SECOND) as u64)
+      .predecessor_account_id(accounts(4))
+      .build());
+    contract.auctions.insert(&"0".to_string(), &sample_auction());
+    contract.loans.insert(&"0".to_string(), &Loan{status: LoanStatus::Liquidating, ..sample_loan(1000, 0)});
+    contract.claim_unsold_collateral("0".to_string());
+    assert!(contract.get_auction("0".to_string()).is_none());
+    assert_eq!(contract.loans.get(&"0".to_string()).unwrap().status, LoanStatus::Defaulted);
+  }
+
+  #[test]
+  #[should_panic(expected = "This auction hasn't ended yet")]
+  fn test_claim_unsold_collateral_before_end() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .block_timestamp((AUCTION_DURATION / 2 * NANOSECONDS_PER_SECOND) as u64)
+      .predecessor_account_id(accounts(4))
+      .build());
+    contract.auctions.insert(&"0".to_string(), &sample_auction());
+    contract.claim_unsold_collateral("0".to_string());
+  }
+
+  #[test]
+  #[should_panic(expected = "Only note contract can call this function")]
+  fn test_liquidate_loan_not_note_contract() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .predecessor_account_id(accounts(4))
+      .build());
+    contract.liquidate_loan("0".to_string(), accounts(4).into());
+  }
+}
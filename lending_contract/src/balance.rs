@@ -10,6 +10,7 @@ impl LendingNftCollateral {
 
   #[payable]
   pub fn deposit_balance(&mut self, value_to_deposit: U128) {
+    assert!(env::attached_deposit() >= value_to_deposit.0, "Attached deposit doesn't cover the value to deposit");
     let current_value = self.get_balance_value(env::predecessor_account_id());
     self.balances.insert(&env::predecessor_account_id(), &(current_value + value_to_deposit.0));
   }
@@ -20,6 +21,87 @@ impl LendingNftCollateral {
     self.balances.insert(&env::predecessor_account_id(), &(current_value - value_to_remove.0));
     Promise::new(env::predecessor_account_id()).transfer(value_to_remove.0);
   }
+
+  pub fn get_ft_balance_value(&self, owner_id: AccountId, ft_contract_id: AccountId) -> U128 {
+    U128(self.ft_balances.get(&(owner_id, ft_contract_id)).unwrap_or(0))
+  }
+
+  pub fn remove_ft_balance(&mut self, ft_contract_id: AccountId, value_to_remove: U128) -> Promise {
+    let owner_id = env::predecessor_account_id();
+    let currency = Some(ft_contract_id);
+    let current_value = self.get_funds(owner_id.clone(), &currency);
+    assert!(value_to_remove.0 <= current_value, "You don't have enough credit to remove");
+    self.set_funds(owner_id.clone(), &currency, current_value - value_to_remove.0);
+    self.pay_out(owner_id, &currency, value_to_remove.0)
+  }
+}
+
+impl LendingNftCollateral {
+
+  // takes the value from the attached deposit, any excess is credited to the balance
+  // and any shortfall is debited from it
+  pub fn lock_funds(&mut self, owner_id: AccountId, value_to_lock: u128) {
+    let attached_deposit = env::attached_deposit();
+    let current_value = self.get_balance_value(owner_id.clone());
+    if attached_deposit >= value_to_lock {
+      self.balances.insert(&owner_id, &(current_value + attached_deposit - value_to_lock));
+    } else {
+      let missing_value = value_to_lock - attached_deposit;
+      assert!(missing_value <= current_value, "You don't have enough credit for this transaction");
+      self.balances.insert(&owner_id, &(current_value - missing_value));
+    }
+  }
+
+  pub fn release_funds(&mut self, owner_id: AccountId, value_to_release: u128) -> Promise {
+    Promise::new(owner_id).transfer(value_to_release)
+  }
+
+  pub fn credit_balance(&mut self, owner_id: AccountId, value_to_credit: u128) {
+    let current_value = self.get_balance_value(owner_id.clone());
+    self.balances.insert(&owner_id, &(current_value + value_to_credit));
+  }
+
+  // balances by currency, None is NEAR and Some is the fungible token contract
+  pub fn get_funds(&self, owner_id: AccountId, currency: &Option<AccountId>) -> u128 {
+    match currency {
+      Some(ft_contract_id) => self.ft_balances.get(&(owner_id, ft_contract_id.clone())).unwrap_or(0),
+      None => self.balances.get(&owner_id).unwrap_or(0)
+    }
+  }
+
+  pub fn set_funds(&mut self, owner_id: AccountId, currency: &Option<AccountId>, value: u128) {
+    match currency {
+      Some(ft_contract_id) => self.ft_balances.insert(&(owner_id, ft_contract_id.clone()), &value),
+      None => self.balances.insert(&owner_id, &value)
+    };
+  }
+
+  pub fn credit_funds(&mut self, owner_id: AccountId, currency: &Option<AccountId>, value_to_credit: u128) {
+    let current_value = self.get_funds(owner_id.clone(), currency);
+    self.set_funds(owner_id, currency, current_value + value_to_credit);
+  }
+
+  // fungible token transfers that fail are credited back so the receiver can withdraw them later
+  pub fn pay_out(&mut self, receiver_id: AccountId, currency: &Option<AccountId>, value: u128) -> Promise {
+    match currency {
+      Some(ft_contract_id) => ext_ft_contract::ft_transfer(
+        receiver_id.clone(),
+        U128(value),
+        None,
+        ft_contract_id,
+        ONE_YOCTO,
+        FT_TRANSFER_GAS
+      ).then(ext_self::resolve_ft_payout(
+        receiver_id,
+        ft_contract_id.clone(),
+        U128(value),
+        &env::current_account_id(),
+        NO_DEPOSIT,
+        BASE_GAS
+      )),
+      None => self.release_funds(receiver_id, value)
+    }
+  }
 }
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
@@ -92,4 +174,57 @@ mod tests {
     let result = contract.balances.get(&accounts(0).to_string()).unwrap_or(0);
     assert_eq!(result, 30);
   }
-}
\ No newline at end of file
+
+  #[test]
+  #[should_panic(expected = "Attached deposit doesn't cover the value to deposit")]
+  fn test_deposit_balance_without_deposit() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .attached_deposit(10)
+      .predecessor_account_id(accounts(0))
+      .build());
+
+    contract.deposit_balance(U128(20));
+  }
+
+  #[test]
+  fn test_lock_funds() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // excess deposit is credited
+    testing_env!(context
+      .attached_deposit(30)
+      .predecessor_account_id(accounts(0))
+      .build());
+    contract.lock_funds(accounts(0).into(), 20);
+    assert_eq!(contract.balances.get(&accounts(0).to_string()).unwrap(), 10);
+
+    // missing deposit is debited
+    testing_env!(context
+      .attached_deposit(5)
+      .predecessor_account_id(accounts(0))
+      .build());
+    contract.lock_funds(accounts(0).into(), 12);
+    assert_eq!(contract.balances.get(&accounts(0).to_string()).unwrap(), 3);
+  }
+
+  #[test]
+  #[should_panic(expected = "You don't have enough credit for this transaction")]
+  fn test_lock_funds_not_enough_credit() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .attached_deposit(5)
+      .predecessor_account_id(accounts(0))
+      .build());
+    contract.balances.insert(&accounts(0).into(), &(4));
+    contract.lock_funds(accounts(0).into(), 10);
+  }
+}
@@ -0,0 +1,211 @@
+use crate::*;
+
+// each piece is transferred separately with BASE_GAS next to the two burns, the tightest budget is
+// the note contract's 30T call to transfer_warranty_loan, which fits three pieces
+const MAX_BUNDLE_SIZE: usize = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Collateral {
+  pub nft_collection_id: NftCollection,
+  pub token_id: TokenId
+}
+
+impl Loan {
+
+  // the main NFT followed by the rest of the bundle
+  pub fn collateral(&self) -> Vec<Collateral> {
+    let mut collateral = vec![Collateral{nft_collection_id: self.warranty_collection.clone(), token_id: self.warranty_token_id.clone()}];
+    collateral.extend(self.bundled_collateral.clone());
+    collateral
+  }
+}
+
+impl Offer {
+
+  // what a borrowing offer holds in escrow, its NFT followed by the rest of the bundle
+  pub fn collateral(&self, nft_collection_id: &NftCollection) -> Vec<Collateral> {
+    let mut collateral = vec![Collateral{nft_collection_id: nft_collection_id.clone(), token_id: self.token_id.clone().expect("This offer has no collateral")}];
+    collateral.extend(self.bundled_collateral.clone());
+    collateral
+  }
+}
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // turns the caller's pending bundle into a borrowing offer on one of the bundle's collections,
+  // the value can't exceed the sum of the limits of every NFT in the bundle
+  pub fn post_bundle_borrowing_offer(&mut self, nft_collection_id: NftCollection, value_offered: U128, apr: U128, loan_duration: U128, currency: Option<AccountId>, expires_at: Option<U128>) -> bool {
+    let owner_id = env::predecessor_account_id();
+    let mut bundle = self.pending_bundles.get(&owner_id).expect("You don't have a pending bundle");
+    assert!(value_offered.0 <= self.calculate_bundle_loan_limit(&bundle), "The value requested is higher than the bundle's limit");
+    let main_index = bundle.iter()
+      .position(|collateral| collateral.nft_collection_id == nft_collection_id)
+      .expect("The bundle doesn't have any NFT from this collection");
+    let main_collateral = bundle.remove(main_index);
+    self.pending_bundles.remove(&owner_id);
+    let borrowing_offer = Offer{owner_id, value: value_offered.0, token_id: Some(main_collateral.token_id), apr: apr.0, loan_duration: loan_duration.0, bundled_collateral: bundle, currency, expires_at: expires_at.map(|timestamp| timestamp.0), ..Default::default()};
+    self.place_borrowing_offer(nft_collection_id, borrowing_offer)
+  }
+
+  // returns every NFT of the caller's pending bundle
+  pub fn withdraw_pending_bundle(&mut self) -> Promise {
+    let owner_id = env::predecessor_account_id();
+    let bundle = self.pending_bundles.remove(&owner_id).expect("You don't have a pending bundle");
+    self.transfer_collateral(owner_id, bundle)
+  }
+
+  pub fn get_pending_bundle(&self, owner_id: AccountId) -> Vec<Collateral> {
+    self.pending_bundles.get(&owner_id).unwrap_or_default()
+  }
+
+  pub fn get_bundle_loan_limit(&self, owner_id: AccountId) -> U128 {
+    U128(self.calculate_bundle_loan_limit(&self.get_pending_bundle(owner_id)))
+  }
+}
+
+impl LendingNftCollateral {
+
+  // called from nft_on_transfer, the NFT is already held by the contract
+  pub fn add_to_pending_bundle(&mut self, nft_collection_id: NftCollection, token_id: TokenId, owner_id: AccountId) {
+    self.assert_collection_enabled(&nft_collection_id);
+    let mut bundle = self.pending_bundles.get(&owner_id).unwrap_or_default();
+    assert!(bundle.len() < MAX_BUNDLE_SIZE, "This bundle already has the maximum number of NFTs");
+    let collateral = Collateral{nft_collection_id, token_id};
+    self.commit_collateral(std::slice::from_ref(&collateral));
+    bundle.push(collateral);
+    self.pending_bundles.insert(&owner_id, &bundle);
+  }
+
+  pub fn calculate_bundle_loan_limit(&self, bundle: &[Collateral]) -> u128 {
+    bundle.iter()
+      .map(|collateral| self.collection_params.get(&collateral.nft_collection_id).map_or(0, |params| params.max_loan_value))
+      .sum()
+  }
+
+  // an NFT stays committed from its deposit until it leaves the contract
+  pub fn commit_collateral(&mut self, collateral: &[Collateral]) {
+    for piece in collateral {
+      assert!(self.committed_collateral.insert(&(piece.nft_collection_id.clone(), piece.token_id.clone())), "This NFT already backs another offer or loan");
+    }
+  }
+
+  pub fn release_collateral(&mut self, collateral: &[Collateral]) {
+    for piece in collateral {
+      self.committed_collateral.remove(&(piece.nft_collection_id.clone(), piece.token_id.clone()));
+    }
+  }
+
+  pub fn transfer_collateral(&mut self, receiver_id: AccountId, collateral: Vec<Collateral>) -> Promise {
+    self.release_collateral(&collateral);
+    collateral.into_iter()
+      .map(|collateral| ext_nft_contract::nft_transfer(
+        receiver_id.clone(),
+        collateral.token_id,
+        None,
+        None,
+        &collateral.nft_collection_id,
+        ONE_YOCTO,
+        BASE_GAS
+      ))
+      .reduce(|promise, transfer| promise.and(transfer))
+      .expect("There is no collateral to transfer")
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::MockedBlockchain;
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  fn setup_collections(contract: &mut LendingNftCollateral) {
+    contract.collection_params.insert(&"nft_collection_test1".to_string(), &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()});
+    contract.collection_params.insert(&"nft_collection_test2".to_string(), &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 50, max_apr: 10000, enabled: true, ..Default::default()});
+  }
+
+  #[test]
+  fn test_add_to_pending_bundle() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+    setup_collections(&mut contract);
+
+    contract.add_to_pending_bundle("nft_collection_test1".to_string(), "token_id1".to_string(), accounts(4).into());
+    contract.add_to_pending_bundle("nft_collection_test2".to_string(), "token_id2".to_string(), accounts(4).into());
+    contract.add_to_pending_bundle("nft_collection_test2".to_string(), "token_id3".to_string(), accounts(4).into());
+    assert_eq!(contract.get_pending_bundle(accounts(4).into()).len(), 3);
+    assert_eq!(contract.get_bundle_loan_limit(accounts(4).into()).0, 200);
+  }
+
+  #[test]
+  #[should_panic(expected = "This bundle already has the maximum number of NFTs")]
+  fn test_add_to_pending_bundle_full() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+    setup_collections(&mut contract);
+
+    for token_id in ["token_id1", "token_id2", "token_id3", "token_id4"] {
+      contract.add_to_pending_bundle("nft_collection_test1".to_string(), token_id.to_string(), accounts(4).into());
+    }
+  }
+
+  #[test]
+  #[should_panic(expected = "This collection is not accepted as collateral")]
+  fn test_add_to_pending_bundle_unknown_collection() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.add_to_pending_bundle("nft_collection_test1".to_string(), "token_id1".to_string(), accounts(4).into());
+  }
+
+  #[test]
+  fn test_post_bundle_borrowing_offer() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+    setup_collections(&mut contract);
+
+    contract.add_to_pending_bundle("nft_collection_test1".to_string(), "token_id1".to_string(), accounts(4).into());
+    contract.add_to_pending_bundle("nft_collection_test2".to_string(), "token_id2".to_string(), accounts(4).into());
+    testing_env!(context
+      .predecessor_account_id(accounts(4))
+      .build());
+    let success = contract.post_bundle_borrowing_offer("nft_collection_test2".to_string(), U128(150), U128(500), U128(604800), None, None);
+    assert!(success);
+    assert!(contract.get_pending_bundle(accounts(4).into()).is_empty());
+    let offer = contract.borrowing_offers.get(&"nft_collection_test2".to_string()).unwrap().get(&"0".to_string()).unwrap();
+    assert_eq!(offer.token_id, Some("token_id2".to_string()));
+    assert_eq!(offer.bundled_collateral, vec![Collateral{nft_collection_id: "nft_collection_test1".to_string(), token_id: "token_id1".to_string()}]);
+  }
+
+  #[test]
+  #[should_panic(expected = "The value requested is higher than the bundle's limit")]
+  fn test_post_bundle_borrowing_offer_over_limit() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+    setup_collections(&mut contract);
+
+    contract.add_to_pending_bundle("nft_collection_test1".to_string(), "token_id1".to_string(), accounts(4).into());
+    contract.add_to_pending_bundle("nft_collection_test2".to_string(), "token_id2".to_string(), accounts(4).into());
+    testing_env!(context
+      .predecessor_account_id(accounts(4))
+      .build());
+    contract.post_bundle_borrowing_offer("nft_collection_test1".to_string(), U128(151), U128(500), U128(604800), None, None);
+  }
+}
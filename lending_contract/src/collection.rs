@@ -0,0 +1,293 @@
+use crate::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionParams {
+  // loan durations in seconds
+  pub min_loan_duration: u128,
+  pub max_loan_duration: u128,
+  // seconds after expiration during which the borrower can still repay before the collateral can be claimed
+  pub grace_period: u128,
+  // charged once over the loan value when it's repaid after expiration, in basis points
+  pub late_fee: u128,
+  // the most a single NFT of the collection can borrow, also what it adds to a bundle's borrowing limit
+  pub max_loan_value: u128,
+  // annual interest rates in basis points that offers on the collection can ask for
+  pub min_apr: u128,
+  pub max_apr: u128,
+  // loans against the collection can't be worth more than this share of the collateral's floor
+  // price, in basis points. None leaves loans uncapped by the oracle
+  pub max_ltv: Option<u128>,
+  // a disabled collection takes no new offers, its loans carry on until they're closed
+  pub enabled: bool
+}
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  pub fn add_collection(&mut self, nft_collection_id: NftCollection, collection_params: CollectionParams) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    assert!(self.collection_params.get(&nft_collection_id).is_none(), "This collection is already registered");
+    assert_valid_collection_params(&collection_params);
+    self.collection_params.insert(&nft_collection_id, &collection_params);
+  }
+
+  // the new parameters apply to offers posted from now on, active loans keep the grace period
+  // and late fee read when they're repaid or claimed
+  pub fn alter_collection(&mut self, nft_collection_id: NftCollection, collection_params: CollectionParams) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    assert!(self.collection_params.get(&nft_collection_id).is_some(), "This collection is not accepted as collateral");
+    assert_valid_collection_params(&collection_params);
+    self.collection_params.insert(&nft_collection_id, &collection_params);
+  }
+
+  // loans read the collection's grace period and late fee, so only collections without active
+  // loans can be removed. Disable the collection to stop new offers instead
+  pub fn remove_collection(&mut self, nft_collection_id: NftCollection) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    let has_active_loans = match self.loans_by_collection.get(&nft_collection_id) {
+      Some(token_ids) => !token_ids.is_empty(),
+      None => false
+    };
+    assert!(!has_active_loans, "This collection still has active loans");
+    self.collection_params.remove(&nft_collection_id).expect("This collection is not accepted as collateral");
+  }
+
+  pub fn set_collection_enabled(&mut self, nft_collection_id: NftCollection, enabled: bool) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    let mut collection_params = self.collection_params.get(&nft_collection_id).expect("This collection is not accepted as collateral");
+    collection_params.enabled = enabled;
+    self.collection_params.insert(&nft_collection_id, &collection_params);
+  }
+
+  pub fn set_collection_loan_duration_range(&mut self, nft_collection_id: NftCollection, min_loan_duration: U128, max_loan_duration: U128) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    assert!(min_loan_duration.0 <= max_loan_duration.0, "Minimum duration can't be higher than maximum duration");
+    let mut collection_params = self.collection_params.get(&nft_collection_id).expect("This collection is not accepted as collateral");
+    collection_params.min_loan_duration = min_loan_duration.0;
+    collection_params.max_loan_duration = max_loan_duration.0;
+    self.collection_params.insert(&nft_collection_id, &collection_params);
+  }
+
+  pub fn set_collection_grace_period(&mut self, nft_collection_id: NftCollection, grace_period: U128, late_fee: U128) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    assert!(late_fee.0 <= BASIS_POINTS, "Late fee can't be higher than the loan value");
+    let mut collection_params = self.collection_params.get(&nft_collection_id).expect("This collection is not accepted as collateral");
+    collection_params.grace_period = grace_period.0;
+    collection_params.late_fee = late_fee.0;
+    self.collection_params.insert(&nft_collection_id, &collection_params);
+  }
+
+  pub fn set_collection_max_loan_value(&mut self, nft_collection_id: NftCollection, max_loan_value: U128) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    let mut collection_params = self.collection_params.get(&nft_collection_id).expect("This collection is not accepted as collateral");
+    collection_params.max_loan_value = max_loan_value.0;
+    self.collection_params.insert(&nft_collection_id, &collection_params);
+  }
+
+  pub fn get_collection_params(&self, nft_collection_id: NftCollection) -> Option<CollectionParams> {
+    self.collection_params.get(&nft_collection_id)
+  }
+}
+
+impl LendingNftCollateral {
+
+  pub fn assert_valid_loan_duration(&self, nft_collection_id: &NftCollection, loan_duration: u128) {
+    let collection_params = self.collection_params.get(nft_collection_id).expect("Loan durations are not defined for this collection");
+    assert!(
+      loan_duration >= collection_params.min_loan_duration && loan_duration <= collection_params.max_loan_duration,
+      "Loan duration is outside of the collection's allowed range"
+    );
+  }
+
+  // what every new offer on the collection has to respect, the value of a borrowing offer is
+  // checked along with its collateral
+  pub fn assert_valid_offer_terms(&self, nft_collection_id: &NftCollection, offer: &Offer) {
+    let collection_params = self.assert_collection_enabled(nft_collection_id);
+    self.assert_valid_loan_duration(nft_collection_id, offer.loan_duration);
+    assert!(
+      offer.apr >= collection_params.min_apr && offer.apr <= collection_params.max_apr,
+      "APR is outside of the collection's allowed range"
+    );
+  }
+
+  pub fn assert_collection_enabled(&self, nft_collection_id: &NftCollection) -> CollectionParams {
+    let collection_params = self.collection_params.get(nft_collection_id).expect("This collection is not accepted as collateral");
+    assert!(collection_params.enabled, "This collection is not accepting new offers");
+    collection_params
+  }
+
+  pub fn assert_valid_loan_value(&self, nft_collection_id: &NftCollection, loan_value: u128) {
+    let collection_params = self.collection_params.get(nft_collection_id).expect("This collection is not accepted as collateral");
+    assert!(loan_value <= collection_params.max_loan_value, "The value is higher than the collection's maximum loan value");
+  }
+}
+
+fn assert_valid_collection_params(collection_params: &CollectionParams) {
+  assert!(collection_params.min_loan_duration <= collection_params.max_loan_duration, "Minimum duration can't be higher than maximum duration");
+  assert!(collection_params.min_apr <= collection_params.max_apr, "Minimum APR can't be higher than maximum APR");
+  assert!(collection_params.late_fee <= BASIS_POINTS, "Late fee can't be higher than the loan value");
+  if let Some(max_ltv) = collection_params.max_ltv {
+    assert!(max_ltv <= BASIS_POINTS, "Maximum loan-to-value can't be higher than the floor price");
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::MockedBlockchain;
+
+  use super::*;
+  use crate::loan::tests::sample_loan;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  #[test]
+  fn test_set_collection_loan_duration_range() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.add_collection(nft_collection_id.clone(), sample_collection_params());
+    contract.set_collection_loan_duration_range(nft_collection_id.clone(), U128(86400), U128(2592000));
+    let collection_params = contract.get_collection_params(nft_collection_id.clone()).unwrap();
+    assert_eq!(collection_params.min_loan_duration, 86400);
+    assert_eq!(collection_params.max_loan_duration, 2592000);
+  }
+
+  #[test]
+  #[should_panic(expected = "This collection is not accepted as collateral")]
+  fn test_set_collection_loan_duration_range_unknown_collection() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.set_collection_loan_duration_range("nft_collection_test".to_string(), U128(604800), U128(7776000));
+  }
+
+  #[test]
+  fn test_set_collection_grace_period() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.add_collection(nft_collection_id.clone(), sample_collection_params());
+    contract.set_collection_grace_period(nft_collection_id.clone(), U128(172800), U128(500));
+    // changing the durations keeps the grace period
+    contract.set_collection_loan_duration_range(nft_collection_id.clone(), U128(86400), U128(7776000));
+    let collection_params = contract.get_collection_params(nft_collection_id.clone()).unwrap();
+    assert_eq!(collection_params.min_loan_duration, 86400);
+    assert_eq!(collection_params.grace_period, 172800);
+    assert_eq!(collection_params.late_fee, 500);
+  }
+
+  #[test]
+  #[should_panic(expected = "Only owner can call this function")]
+  fn test_set_collection_loan_duration_range_not_owner() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .predecessor_account_id(accounts(0))
+      .build());
+    contract.set_collection_loan_duration_range("nft_collection_test".to_string(), U128(604800), U128(7776000));
+  }
+
+  #[test]
+  #[should_panic(expected = "Loan duration is outside of the collection's allowed range")]
+  fn test_assert_valid_loan_duration() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.add_collection(nft_collection_id.clone(), sample_collection_params());
+    contract.assert_valid_loan_duration(&nft_collection_id, 604800);
+    contract.assert_valid_loan_duration(&nft_collection_id, 86400);
+  }
+
+  fn sample_collection_params() -> CollectionParams {
+    CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, min_apr: 100, max_apr: 2000, enabled: true, ..Default::default()}
+  }
+
+  #[test]
+  fn test_collection_registry() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.add_collection(nft_collection_id.clone(), sample_collection_params());
+    contract.alter_collection(nft_collection_id.clone(), CollectionParams{max_apr: 3000, ..sample_collection_params()});
+    assert_eq!(contract.get_collection_params(nft_collection_id.clone()).unwrap().max_apr, 3000);
+    contract.set_collection_enabled(nft_collection_id.clone(), false);
+    assert!(!contract.get_collection_params(nft_collection_id.clone()).unwrap().enabled);
+    contract.remove_collection(nft_collection_id.clone());
+    assert!(contract.get_collection_params(nft_collection_id).is_none());
+  }
+
+  #[test]
+  #[should_panic(expected = "This collection still has active loans")]
+  fn test_remove_collection_with_active_loans() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.add_collection(nft_collection_id.clone(), sample_collection_params());
+    let loan = Loan{status: LoanStatus::Active, warranty_collection: nft_collection_id.clone(), ..sample_loan(10, 1000)};
+    contract.update_loan_indexes(&"0".to_string(), &loan);
+    contract.remove_collection(nft_collection_id);
+  }
+
+  #[test]
+  #[should_panic(expected = "APR is outside of the collection's allowed range")]
+  fn test_post_lending_offer_outside_apr_range() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.add_collection(nft_collection_id.clone(), sample_collection_params());
+    testing_env!(context
+      .attached_deposit(10)
+      .predecessor_account_id(accounts(4))
+      .build());
+    contract.post_lending_offer(nft_collection_id, U128(10), U128(2500), U128(604800), None, None);
+  }
+
+  #[test]
+  #[should_panic(expected = "This collection is not accepting new offers")]
+  fn test_post_borrowing_offer_disabled_collection() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.add_collection(nft_collection_id.clone(), CollectionParams{enabled: false, ..sample_collection_params()});
+    contract.place_borrowing_offer(nft_collection_id, Offer{owner_id: accounts(4).into(), value: 10, token_id: Some("token_id".to_string()), apr: 1000, loan_duration: 604800, ..Default::default()});
+  }
+
+  #[test]
+  #[should_panic(expected = "The value is higher than the collection's maximum loan value")]
+  fn test_post_borrowing_offer_above_max_loan_value() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.add_collection(nft_collection_id.clone(), sample_collection_params());
+    contract.place_borrowing_offer(nft_collection_id, Offer{owner_id: accounts(4).into(), value: 150, token_id: Some("token_id".to_string()), apr: 1000, loan_duration: 604800, ..Default::default()});
+  }
+}
@@ -1,131 +1,263 @@
 use crate::*;
 use serde_json::Value;
 
+// bounds the book walk of a single incoming offer, each match schedules a custody check and its
+// callback so no more than two fit in the 300T a transaction carries
+const MAX_MATCHES_PER_OFFER: usize = 2;
+const MAX_OFFERS_SCANNED: usize = 50;
+
 impl LendingNftCollateral {
-  pub fn evaluate_lending_offer_possible_match(&mut self, nft_collection_id: &NftCollection, lending_offer_value: U128) -> bool {
-    match self.get_best_borrowing_offer(nft_collection_id.to_string()) {
-      Some(offer) => lending_offer_value.0 >= offer.value,
-      None => false
+  // walks the borrowing book with price-time priority and takes every offer the incoming lending
+  // offer can still fund. The book is ordered by value so the walk stops at the first offer above
+  // what is left, offers with other terms or above what their collateral can back are skipped
+  pub fn match_lending_offer(&self, nft_collection_id: &NftCollection, lending_offer: &Offer) -> Vec<Offer> {
+    let borrowing_offers_book = self.get_borrowing_offers_book(nft_collection_id);
+    let offer_map = self.get_borrowing_offers_map(nft_collection_id);
+    let mut remaining_value = lending_offer.value;
+    let mut matches = Vec::new();
+    for ((value, _), offer_id) in borrowing_offers_book.iter().take(MAX_OFFERS_SCANNED) {
+      if value > remaining_value || value > lending_offer.loan_value_cap() || matches.len() == MAX_MATCHES_PER_OFFER {
+        break;
+      }
+      let borrowing_offer = offer_map.get(&offer_id).unwrap();
+      if !borrowing_offer.is_expired()
+        && terms_cross(lending_offer, &borrowing_offer)
+        && lending_offer.accepts(&borrowing_offer)
+        && value <= self.get_ltv_loan_limit(nft_collection_id, &borrowing_offer) {
+        remaining_value -= value;
+        matches.push(borrowing_offer);
+      }
     }
+    matches
   }
 
-  pub fn evaluate_borrowing_offer_possible_match(&mut self, nft_collection_id: &NftCollection, borrowing_offer_value: U128) -> bool {
-    match self.get_best_lending_offer(nft_collection_id.to_string()) {
-      Some(offer) => borrowing_offer_value.0 <= offer.value,
-      None => false
+  // the first lending offer in price-time priority that covers the whole borrowing offer in a single
+  // loan. Offers targeting the collateral are looked at before the collection-wide ones
+  pub fn match_borrowing_offer(&self, nft_collection_id: &NftCollection, borrowing_offer: &Offer) -> Option<Offer> {
+    // an offer above what its collateral can back rests until the floor price allows it
+    if borrowing_offer.value > self.get_ltv_loan_limit(nft_collection_id, borrowing_offer) {
+      return None;
     }
+    let offer_map = self.get_lending_offers_map(nft_collection_id);
+    find_lending_offer(&self.get_targeted_lending_offers_book(nft_collection_id), &offer_map, borrowing_offer)
+      .or_else(|| find_lending_offer(&self.get_lending_offers_book(nft_collection_id), &offer_map, borrowing_offer))
   }
 
-  pub fn reorder_vec_without_specific_offer(&mut self, offers_vec: &mut Vector<Offer> , offer_to_remove: Offer) {
-    let mut append_vec = Vec::new();
-    let mut counter = if offers_vec.len() == 0 {offers_vec.len()} else {offers_vec.len() - 1};
-    loop {
-      match offers_vec.get(counter) {
-        Some(offer) => {
-          if offer.offer_id != offer_to_remove.offer_id {
-            append_vec.push(offers_vec.pop().unwrap());
-            if counter > 0 {
-              counter = counter - 1;
-            }
-          } else {
-            offers_vec.pop();
-            break
-          }
-        },
-        None => {
-          break
-        }
+  // the incoming offer takes resting offers at their terms, returns the part that wasn't lent.
+  // Matching stops once the gas left can't pay for another loan, the rest stays in the book
+  pub fn fill_lending_offer(&mut self, nft_collection_id: NftCollection, lending_offer: Offer) -> Offer {
+    let mut lending_offer = lending_offer;
+    for borrowing_offer in self.match_lending_offer(&nft_collection_id, &lending_offer) {
+      let loan_gas = BASE_GAS * borrowing_offer.collateral(&nft_collection_id).len() as Gas + CUSTODY_CALLBACK_GAS;
+      if env::prepaid_gas() - env::used_gas() < loan_gas + BASE_GAS {
+        break;
       }
+      self.remove_borrowing_offer(nft_collection_id.clone(), borrowing_offer.offer_id.clone());
+      let matched_lending_offer = Offer{value: borrowing_offer.value, apr: borrowing_offer.apr, ..lending_offer.clone()};
+      lending_offer.value -= borrowing_offer.value;
+      self.post_loan(nft_collection_id.clone(), matched_lending_offer, borrowing_offer.clone(), false);
     }
-    let mut reverse_vec = append_vec;
-    reverse_vec.reverse();
-    offers_vec.extend(reverse_vec.into_iter());
-  }
-
-  pub fn get_lending_offers_vec_from_nft_collection(&mut self, nft_collection_id: NftCollection) -> Vector<Offer> {
-    let mut lending_offers_vec = match self.lending_offers_vecs.get(&nft_collection_id) {
-      Some(value) => value,
-      None => {
-        let mut vector_id = nft_collection_id.clone();
-        vector_id.push_str("lending");
-        let new_vec = Vector::new(vector_id.into_bytes().to_vec());
-        self.lending_offers_vecs.insert(&nft_collection_id, &new_vec);
-        new_vec
-      }
-    };
-    lending_offers_vec
-  }
-
-  pub fn get_borrowing_offers_vec_from_nft_collection(&mut self, nft_collection_id: NftCollection) -> Vector<Offer> {
-    let mut borrowing_offers_vec = match self.borrowing_offers_vecs.get(&nft_collection_id) {
-      Some(value) => value,
-      None => {
-        let mut vector_id = nft_collection_id.clone();
-        vector_id.push_str("borrowing");
-        let new_vec = Vector::new(vector_id.into_bytes().to_vec());
-        self.borrowing_offers_vecs.insert(&nft_collection_id, &new_vec);
-        new_vec
-      }
-    };
-    borrowing_offers_vec
-  }
-
-  pub fn sort_order_lending_offer_vec(&mut self, lending_offers_vec: Vector<Offer> , new_offer: Offer) -> Vector<Offer> {
-    let mut lending_offers_vec = lending_offers_vec;
-    let mut append_vec = Vec::new();
-    let mut counter = if lending_offers_vec.len() == 0 {lending_offers_vec.len()} else {lending_offers_vec.len() - 1};
-    loop {
-      match lending_offers_vec.get(counter) {
-        Some(offer) => {
-          if offer.value >= new_offer.value {
-            append_vec.push(lending_offers_vec.pop().unwrap());
-            if counter > 0 {
-              counter = counter - 1;
-            }
-          } else {
-            lending_offers_vec.push(&new_offer);
-            break
-          }
-        },
-        None => {
-          lending_offers_vec.push(&new_offer);
-          break
-        }
-      };
+    lending_offer
+  }
+
+  // returns whether the incoming offer was funded, the loan is priced at the resting lender's rate
+  pub fn fill_borrowing_offer(&mut self, nft_collection_id: NftCollection, borrowing_offer: &Offer) -> bool {
+    match self.match_borrowing_offer(&nft_collection_id, borrowing_offer) {
+      Some(lending_offer) => {
+        let lending_offer = self.draw_lending_offer(nft_collection_id.clone(), lending_offer.offer_id, borrowing_offer.value);
+        self.post_loan(nft_collection_id, lending_offer, borrowing_offer.clone(), true);
+        true
+      },
+      None => false
     }
-    let mut reverse_vec = append_vec;
-    reverse_vec.reverse();
-    lending_offers_vec.extend(reverse_vec.into_iter());
-    lending_offers_vec
-  }
-
-  pub fn sort_order_borrowing_offer_vec(&mut self, borrowing_offers_vec: Vector<Offer> , new_offer: Offer) -> Vector<Offer> {
-    let mut borrowing_offers_vec = borrowing_offers_vec;
-    let mut append_vec = Vec::new();
-    let mut counter = if borrowing_offers_vec.len() == 0 {borrowing_offers_vec.len()} else {borrowing_offers_vec.len() - 1};
-    loop {
-      match borrowing_offers_vec.get(counter) {
-        Some(offer) => {
-          if offer.value <= new_offer.value {
-            append_vec.push(borrowing_offers_vec.pop().unwrap());
-            if counter > 0 {
-              counter = counter - 1;
-            }
-          } else {
-            borrowing_offers_vec.push(&new_offer);
-            break
-          }
-        },
-        None => {
-          borrowing_offers_vec.push(&new_offer);
-          break
-        }
-      };
+  }
+
+  // offers are indexed in the book by their price and then by arrival, so ties go to the oldest offer
+  pub fn insert_lending_offer(&mut self, nft_collection_id: NftCollection, offer: Offer) {
+    let mut offer = offer;
+    offer.sequence = self.next_offer_sequence();
+    self.put_lending_offer(nft_collection_id, offer);
+  }
+
+  // stores the offer with the sequence it already has
+  pub fn put_lending_offer(&mut self, nft_collection_id: NftCollection, offer: Offer) {
+    let mut lending_offers_book = self.get_lending_book_of(&nft_collection_id, &offer);
+    lending_offers_book.insert(&offer.lending_book_key(), &offer.offer_id);
+    self.save_lending_book_of(&nft_collection_id, &offer, &lending_offers_book);
+    let mut offer_map = self.get_lending_offers_map(&nft_collection_id);
+    offer_map.insert(&offer.offer_id, &offer);
+    self.lending_offers.insert(&nft_collection_id, &offer_map);
+    self.index_offer(&offer.owner_id, (nft_collection_id, OfferSide::Lending, offer.offer_id.clone()));
+  }
+
+  pub fn insert_borrowing_offer(&mut self, nft_collection_id: NftCollection, offer: Offer) {
+    let mut offer = offer;
+    offer.sequence = self.next_offer_sequence();
+    let mut borrowing_offers_book = self.get_borrowing_offers_book(&nft_collection_id);
+    borrowing_offers_book.insert(&offer.borrowing_book_key(), &offer.offer_id);
+    self.borrowing_offers_books.insert(&nft_collection_id, &borrowing_offers_book);
+    let mut offer_map = self.get_borrowing_offers_map(&nft_collection_id);
+    offer_map.insert(&offer.offer_id, &offer);
+    self.borrowing_offers.insert(&nft_collection_id, &offer_map);
+    self.index_offer(&offer.owner_id, (nft_collection_id, OfferSide::Borrowing, offer.offer_id.clone()));
+  }
+
+  pub fn remove_lending_offer(&mut self, nft_collection_id: NftCollection, offer_id: String) -> Offer {
+    let mut offer_map = self.lending_offers.get(&nft_collection_id).expect("Offer not found");
+    let lending_offer = offer_map.remove(&offer_id).expect("Offer not found");
+    let mut lending_offers_book = self.get_lending_book_of(&nft_collection_id, &lending_offer);
+    lending_offers_book.remove(&lending_offer.lending_book_key());
+    self.save_lending_book_of(&nft_collection_id, &lending_offer, &lending_offers_book);
+    self.unindex_offer(&lending_offer.owner_id, &(nft_collection_id, OfferSide::Lending, lending_offer.offer_id.clone()));
+    lending_offer
+  }
+
+  // takes value out of a lending offer for a single loan, what's left stays in the book without
+  // losing its arrival order
+  pub fn draw_lending_offer(&mut self, nft_collection_id: NftCollection, offer_id: String, value: u128) -> Offer {
+    let lending_offer = self.remove_lending_offer(nft_collection_id.clone(), offer_id);
+    assert!(!lending_offer.is_expired(), "This offer has expired");
+    assert!(value <= lending_offer.value, "The offer doesn't have enough capacity left");
+    assert!(value <= lending_offer.loan_value_cap(), "The value is above the offer's maximum per loan");
+    if lending_offer.value > value {
+      self.put_lending_offer(nft_collection_id, Offer{value: lending_offer.value - value, ..lending_offer.clone()});
+    }
+    Offer{value, ..lending_offer}
+  }
+
+  // gives a drawn value back to its lending offer, or puts the offer back in the book if it was
+  // used up in the meantime
+  pub fn restore_lending_offer(&mut self, nft_collection_id: NftCollection, offer: Offer) {
+    match self.get_lending_offers_map(&nft_collection_id).get(&offer.offer_id) {
+      Some(resting_offer) if resting_offer.owner_id == offer.owner_id => {
+        self.remove_lending_offer(nft_collection_id.clone(), offer.offer_id.clone());
+        self.put_lending_offer(nft_collection_id, Offer{value: resting_offer.value + offer.value, ..resting_offer});
+      },
+      _ => self.insert_lending_offer(nft_collection_id, offer)
     }
-    let mut reverse_vec = append_vec;
-    reverse_vec.reverse();
-    borrowing_offers_vec.extend(reverse_vec.into_iter());
-    borrowing_offers_vec
+  }
+
+  pub fn remove_borrowing_offer(&mut self, nft_collection_id: NftCollection, offer_id: String) -> Offer {
+    let mut offer_map = self.borrowing_offers.get(&nft_collection_id).expect("Offer not found");
+    let borrowing_offer = offer_map.remove(&offer_id).expect("Offer not found");
+    let mut borrowing_offers_book = self.get_borrowing_offers_book(&nft_collection_id);
+    borrowing_offers_book.remove(&borrowing_offer.borrowing_book_key());
+    self.borrowing_offers_books.insert(&nft_collection_id, &borrowing_offers_book);
+    self.unindex_offer(&borrowing_offer.owner_id, &(nft_collection_id, OfferSide::Borrowing, borrowing_offer.offer_id.clone()));
+    borrowing_offer
+  }
+
+  pub fn get_lending_offers_book(&self, nft_collection_id: &NftCollection) -> TreeMap<(u128, u64), String> {
+    self.lending_offers_books.get(nft_collection_id).unwrap_or_else(|| {
+      let mut book_id = nft_collection_id.clone();
+      book_id.push_str("lending_book");
+      TreeMap::new(book_id.into_bytes())
+    })
+  }
+
+  pub fn get_targeted_lending_offers_book(&self, nft_collection_id: &NftCollection) -> TreeMap<(u128, u64), String> {
+    self.targeted_lending_offers_books.get(nft_collection_id).unwrap_or_else(|| {
+      let mut book_id = nft_collection_id.clone();
+      book_id.push_str("targeted_lending_book");
+      TreeMap::new(book_id.into_bytes())
+    })
+  }
+
+  // targeted offers rest in their own book so the collection-wide walk never has to skip them
+  pub fn get_lending_book_of(&self, nft_collection_id: &NftCollection, offer: &Offer) -> TreeMap<(u128, u64), String> {
+    match offer.target {
+      Some(_) => self.get_targeted_lending_offers_book(nft_collection_id),
+      None => self.get_lending_offers_book(nft_collection_id)
+    }
+  }
+
+  fn save_lending_book_of(&mut self, nft_collection_id: &NftCollection, offer: &Offer, book: &TreeMap<(u128, u64), String>) {
+    match offer.target {
+      Some(_) => self.targeted_lending_offers_books.insert(nft_collection_id, book),
+      None => self.lending_offers_books.insert(nft_collection_id, book)
+    };
+  }
+
+  pub fn get_borrowing_offers_book(&self, nft_collection_id: &NftCollection) -> TreeMap<(u128, u64), String> {
+    self.borrowing_offers_books.get(nft_collection_id).unwrap_or_else(|| {
+      let mut book_id = nft_collection_id.clone();
+      book_id.push_str("borrowing_book");
+      TreeMap::new(book_id.into_bytes())
+    })
+  }
+
+  // each collection keeps its offers under its own prefix
+  pub fn get_lending_offers_map(&self, nft_collection_id: &NftCollection) -> LookupMap<String, Offer> {
+    self.lending_offers.get(nft_collection_id).unwrap_or_else(|| {
+      let mut map_id = nft_collection_id.clone();
+      map_id.push_str("lending_offers");
+      LookupMap::new(map_id.into_bytes())
+    })
+  }
+
+  pub fn get_borrowing_offers_map(&self, nft_collection_id: &NftCollection) -> LookupMap<String, Offer> {
+    self.borrowing_offers.get(nft_collection_id).unwrap_or_else(|| {
+      let mut map_id = nft_collection_id.clone();
+      map_id.push_str("borrowing_offers");
+      LookupMap::new(map_id.into_bytes())
+    })
+  }
+
+  // book entries from the best offer on, resolved to the stored offers
+  pub fn get_book_offers(&self, book: &TreeMap<(u128, u64), String>, offer_map: &LookupMap<String, Offer>) -> Vec<Offer> {
+    book.iter()
+      .filter_map(|(_, offer_id)| offer_map.get(&offer_id))
+      .collect()
+  }
+
+  pub fn next_offer_sequence(&mut self) -> u64 {
+    let sequence = self.offer_sequence;
+    self.offer_sequence += 1;
+    sequence
+  }
+}
+
+// borrowing offers carry the maximum apr the borrower accepts, so the lender's apr can't be above it
+// and both sides must ask for the same loan duration and currency
+fn terms_cross(lending_offer: &Offer, borrowing_offer: &Offer) -> bool {
+  lending_offer.apr <= borrowing_offer.apr && lending_offer.loan_duration == borrowing_offer.loan_duration && lending_offer.currency == borrowing_offer.currency
+}
+
+fn find_lending_offer(book: &TreeMap<(u128, u64), String>, offer_map: &LookupMap<String, Offer>, borrowing_offer: &Offer) -> Option<Offer> {
+  book.iter()
+    .take(MAX_OFFERS_SCANNED)
+    .take_while(|((price_key, _), _)| u128::MAX - price_key >= borrowing_offer.value)
+    .map(|(_, offer_id)| offer_map.get(&offer_id).unwrap())
+    .find(|lending_offer| {
+      !lending_offer.is_expired()
+        && terms_cross(lending_offer, borrowing_offer)
+        && lending_offer.accepts(borrowing_offer)
+        && borrowing_offer.value <= lending_offer.loan_value_cap()
+    })
+}
+
+impl Offer {
+
+  pub fn is_expired(&self) -> bool {
+    match self.expires_at {
+      Some(expires_at) => expires_at <= env::block_timestamp() as u128,
+      None => false
+    }
+  }
+
+  // the most a lending offer puts into a single loan
+  pub fn loan_value_cap(&self) -> u128 {
+    self.max_value_per_loan.map_or(self.value, |max_value_per_loan| std::cmp::min(max_value_per_loan, self.value))
+  }
+
+  // both books are walked from their lowest key, which is the highest value for lending offers
+  // and the lowest value requested for borrowing offers
+  pub fn lending_book_key(&self) -> (u128, u64) {
+    (u128::MAX - self.value, self.sequence)
+  }
+
+  pub fn borrowing_book_key(&self) -> (u128, u64) {
+    (self.value, self.sequence)
   }
 }
 
@@ -137,8 +269,6 @@ mod tests {
 
   use super::*;
 
-  const MINT_STORAGE_COST: u128 = 5920000000000000000000;
-
   fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
     let mut builder = VMContextBuilder::new();
     builder
@@ -148,253 +278,227 @@ mod tests {
     builder
 }
 
+  fn sample_borrowing_offer(offer_id: &str, value: u128, apr: u128) -> Offer {
+    Offer{offer_id: offer_id.to_string(), owner_id: accounts(1).into(), value, token_id: Some(offer_id.to_string()), apr, loan_duration: 604800, ..Default::default()}
+  }
+
+  fn sample_lending_offer(offer_id: &str, value: u128, apr: u128) -> Offer {
+    Offer{offer_id: offer_id.to_string(), owner_id: accounts(1).into(), value, token_id: None, apr, loan_duration: 604800, ..Default::default()}
+  }
+
   #[test]
-  fn test_evaluate_lending_offer_possible_match() {
-    let mut context = get_context(accounts(1));
+  fn test_match_lending_offer() {
+    let context = get_context(accounts(1));
     testing_env!(context.build());
     let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
 
-    testing_env!(context
-      .storage_usage(env::storage_usage())
-      .attached_deposit(MINT_STORAGE_COST)
-      .predecessor_account_id(accounts(0))
-      .build());
-
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("borrowing");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let borrowing_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 20, token_id: Some("token_id_test1".to_string())};
-    let borrowing_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 10, token_id: Some("token_id_test2".to_string())};
-    new_vec.push(&borrowing_offer1);
-    new_vec.push(&borrowing_offer2);
-    contract.borrowing_offers_vecs.insert(&nft_collection_id, &new_vec);
-
-    let result_true = contract.evaluate_lending_offer_possible_match(&nft_collection_id.clone(), U128(10));
-    let result_true2 = contract.evaluate_lending_offer_possible_match(&nft_collection_id.clone(), U128(15));
-    let result_false = contract.evaluate_lending_offer_possible_match(&nft_collection_id.clone(), U128(5));
-    assert_eq!(result_true, true);
-    assert_eq!(result_true2, true);
-    assert_eq!(result_false, false);
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_borrowing_offer("offer_id_test1", 10, 1000));
+    // same value, later in time
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_borrowing_offer("offer_id_test2", 10, 1000));
+    // apr too low for the lender, skipped
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_borrowing_offer("offer_id_test3", 12, 200));
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_borrowing_offer("offer_id_test4", 15, 1000));
+
+    let lending_offer = sample_lending_offer("", 32, 500);
+    let matches = contract.match_lending_offer(&nft_collection_id, &lending_offer);
+    let offer_ids: Vec<String> = matches.iter().map(|offer| offer.offer_id.clone()).collect();
+    // 12 is left after the first two, which doesn't cover the next compatible offer
+    assert_eq!(offer_ids, vec!["offer_id_test1", "offer_id_test2"]);
+
+    let lending_offer = sample_lending_offer("", 5, 500);
+    assert!(contract.match_lending_offer(&nft_collection_id, &lending_offer).is_empty());
+    let lending_offer = Offer{loan_duration: 2592000, ..sample_lending_offer("", 100, 500)};
+    assert!(contract.match_lending_offer(&nft_collection_id, &lending_offer).is_empty());
   }
 
   #[test]
-  fn test_evaluate_borrowing_offer_possible_match() {
-    let mut context = get_context(accounts(1));
+  fn test_match_borrowing_offer() {
+    let context = get_context(accounts(1));
     testing_env!(context.build());
     let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
-            
-    testing_env!(context
-      .storage_usage(env::storage_usage())
-      .attached_deposit(MINT_STORAGE_COST)
-      .predecessor_account_id(accounts(0))
-      .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("lending");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let lending_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None};
-    let lending_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 10, token_id: None};
-    new_vec.push(&lending_offer1);
-    new_vec.push(&lending_offer2);
-    contract.lending_offers_vecs.insert(&nft_collection_id, &new_vec);
-
-    let result_true = contract.evaluate_borrowing_offer_possible_match(&nft_collection_id.clone(), U128(10));
-    let result_false = contract.evaluate_borrowing_offer_possible_match(&nft_collection_id.clone(), U128(15));
-    let result_true2 = contract.evaluate_borrowing_offer_possible_match(&nft_collection_id.clone(), U128(5));
-    assert_eq!(result_true, true);
-    assert_eq!(result_true2, true);
-    assert_eq!(result_false, false);
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("offer_id_test1", 20, 1500));
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("offer_id_test2", 15, 1000));
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("offer_id_test3", 15, 1000));
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("offer_id_test4", 5, 100));
+
+    // the best offer asks for too much interest, the oldest of the next level wins
+    let borrowing_offer = sample_borrowing_offer("", 10, 1000);
+    assert_eq!(contract.match_borrowing_offer(&nft_collection_id, &borrowing_offer).unwrap().offer_id, "offer_id_test2".to_string());
+    let borrowing_offer = sample_borrowing_offer("", 16, 1000);
+    assert!(contract.match_borrowing_offer(&nft_collection_id, &borrowing_offer).is_none());
+    let borrowing_offer = Offer{currency: Some("usdc.test".to_string()), ..sample_borrowing_offer("", 5, 2000)};
+    assert!(contract.match_borrowing_offer(&nft_collection_id, &borrowing_offer).is_none());
   }
 
   #[test]
-  fn test_reorder_vec_without_specific_lending_offer() {
-    let mut context = get_context(accounts(1));
+  fn test_insert_lending_offer() {
+    let context = get_context(accounts(1));
     testing_env!(context.build());
     let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
-            
-    testing_env!(context
-      .storage_usage(env::storage_usage())
-      .attached_deposit(MINT_STORAGE_COST)
-      .predecessor_account_id(accounts(0))
-      .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("lending");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let lending_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 3, token_id: None};
-    let lending_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 5, token_id: None};
-    let lending_offer3 = Offer{offer_id: "offer_id_test3".to_string(), owner_id: accounts(1).into(), value: 10, token_id: None};
-    let lending_offer4 = Offer{offer_id: "offer_id_test4".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None};
-    new_vec.push(&lending_offer1);
-    new_vec.push(&lending_offer2);
-    new_vec.push(&lending_offer3);
-    new_vec.push(&lending_offer4);
-    contract.lending_offers_vecs.insert(&nft_collection_id, &new_vec);
-    let mut lending_offers_vec = contract.lending_offers_vecs.get(&nft_collection_id).unwrap();
-    contract.reorder_vec_without_specific_offer(&mut lending_offers_vec, lending_offer3);
-    // ta certo isso? é pra ser 4 mesmo?
-    assert_eq!(contract.lending_offers_vecs.get(&nft_collection_id).unwrap().len(), 4);
-    assert_eq!(contract.lending_offers_vecs.get(&nft_collection_id).unwrap().get(0).unwrap().value, 3);
-    assert_eq!(contract.lending_offers_vecs.get(&nft_collection_id).unwrap().get(1).unwrap().value, 5);
-    assert_eq!(contract.lending_offers_vecs.get(&nft_collection_id).unwrap().get(2).unwrap().value, 20);
+    for (offer_id, value) in [("offer_id_test1", 10), ("offer_id_test2", 20), ("offer_id_test3", 5), ("offer_id_test4", 20)].iter() {
+      let offer = Offer{offer_id: offer_id.to_string(), owner_id: accounts(1).into(), value: *value, token_id: None, ..Default::default()};
+      contract.insert_lending_offer(nft_collection_id.clone(), offer);
+    }
+    // highest value first, the older offer wins the tie
+    let book = contract.get_lending_offers_book(&nft_collection_id);
+    let offers = contract.get_book_offers(&book, &contract.get_lending_offers_map(&nft_collection_id));
+    let offer_ids: Vec<String> = offers.iter().map(|offer| offer.offer_id.clone()).collect();
+    assert_eq!(offer_ids, vec!["offer_id_test2", "offer_id_test4", "offer_id_test1", "offer_id_test3"]);
+    assert_eq!(offers[0].sequence, 1);
+    assert_eq!(offers[1].sequence, 3);
   }
 
   #[test]
-  fn test_reorder_vec_without_specific_borrowing_offer() {
-    let mut context = get_context(accounts(1));
+  fn test_insert_borrowing_offer() {
+    let context = get_context(accounts(1));
     testing_env!(context.build());
     let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
-            
-    testing_env!(context
-      .storage_usage(env::storage_usage())
-      .attached_deposit(MINT_STORAGE_COST)
-      .predecessor_account_id(accounts(0))
-      .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("borrowing");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let borrowing_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 50, token_id: Some("token_id1".to_string())};
-    let borrowing_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 20, token_id: Some("token_id2".to_string())};
-    let borrowing_offer3 = Offer{offer_id: "offer_id_test3".to_string(), owner_id: accounts(1).into(), value: 15, token_id: Some("token_id3".to_string())};
-    let borrowing_offer4 = Offer{offer_id: "offer_id_test4".to_string(), owner_id: accounts(1).into(), value: 8, token_id: Some("token_id4".to_string())};
-    new_vec.push(&borrowing_offer1);
-    new_vec.push(&borrowing_offer2);
-    new_vec.push(&borrowing_offer3);
-    new_vec.push(&borrowing_offer4);
-    contract.borrowing_offers_vecs.insert(&nft_collection_id, &new_vec);
-    let mut borrowing_offers_vec = contract.borrowing_offers_vecs.get(&nft_collection_id).unwrap();
-    contract.reorder_vec_without_specific_offer(&mut borrowing_offers_vec, borrowing_offer2);
-    assert_eq!(contract.borrowing_offers_vecs.get(&nft_collection_id).unwrap().get(0).unwrap().value, 50);
-    assert_eq!(contract.borrowing_offers_vecs.get(&nft_collection_id).unwrap().get(1).unwrap().value, 15);
-    assert_eq!(contract.borrowing_offers_vecs.get(&nft_collection_id).unwrap().get(2).unwrap().value, 8);
+    for (offer_id, value) in [("offer_id_test1", 10), ("offer_id_test2", 5), ("offer_id_test3", 20), ("offer_id_test4", 5)].iter() {
+      let offer = Offer{offer_id: offer_id.to_string(), owner_id: accounts(1).into(), value: *value, token_id: Some(offer_id.to_string()), ..Default::default()};
+      contract.insert_borrowing_offer(nft_collection_id.clone(), offer);
+    }
+    // lowest value requested first
+    let book = contract.get_borrowing_offers_book(&nft_collection_id);
+    let offers = contract.get_book_offers(&book, &contract.get_borrowing_offers_map(&nft_collection_id));
+    let offer_ids: Vec<String> = offers.iter().map(|offer| offer.offer_id.clone()).collect();
+    assert_eq!(offer_ids, vec!["offer_id_test2", "offer_id_test4", "offer_id_test1", "offer_id_test3"]);
   }
 
   #[test]
-  fn test_get_lending_offers_vec_from_nft_collection() {
-    let mut context = get_context(accounts(1));
+  fn test_remove_lending_offer() {
+    let context = get_context(accounts(1));
     testing_env!(context.build());
     let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
-            
-    testing_env!(context
-      .storage_usage(env::storage_usage())
-      .attached_deposit(MINT_STORAGE_COST)
-      .predecessor_account_id(accounts(0))
-      .build());
-      
+
     let nft_collection_id = "nft_collection_test".to_string();
-    let offer = Offer{offer_id: "offer_id_test".to_string(), owner_id: accounts(1).into(), value: 10, token_id: None};
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("lending");
-    let mut lending_offers_empty_vec = Vector::new(vector_id.into_bytes().to_vec());
-    lending_offers_empty_vec.push(&offer);
-    contract.lending_offers_vecs.insert(&nft_collection_id, &lending_offers_empty_vec);
-    let result_lending_offers = contract.get_lending_offers_vec_from_nft_collection(nft_collection_id.clone());
-    assert_eq!(result_lending_offers.get(0).unwrap().offer_id, "offer_id_test");
-    assert_eq!(result_lending_offers.get(0).unwrap().owner_id, accounts(1).to_string());
-    assert_eq!(result_lending_offers.get(0).unwrap().value, 10);
+    for (offer_id, value) in [("offer_id_test1", 3), ("offer_id_test2", 5), ("offer_id_test3", 10)].iter() {
+      let offer = Offer{offer_id: offer_id.to_string(), owner_id: accounts(1).into(), value: *value, token_id: None, ..Default::default()};
+      contract.insert_lending_offer(nft_collection_id.clone(), offer);
+    }
+    let removed_offer = contract.remove_lending_offer(nft_collection_id.clone(), "offer_id_test2".to_string());
+    assert_eq!(removed_offer.value, 5);
+    let book = contract.get_lending_offers_book(&nft_collection_id);
+    assert_eq!(book.len(), 2);
+    assert!(contract.get_lending_offers_map(&nft_collection_id).get(&"offer_id_test2".to_string()).is_none());
+    let values: Vec<u128> = contract.get_book_offers(&book, &contract.get_lending_offers_map(&nft_collection_id)).iter().map(|offer| offer.value).collect();
+    assert_eq!(values, vec![10, 3]);
   }
 
   #[test]
-  fn test_get_borrowing_offers_vec_from_nft_collection() {
-    let mut context = get_context(accounts(1));
+  fn test_offer_books_are_kept_per_collection() {
+    let context = get_context(accounts(1));
     testing_env!(context.build());
     let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
-            
-    testing_env!(context
-      .storage_usage(env::storage_usage())
-      .attached_deposit(MINT_STORAGE_COST)
-      .predecessor_account_id(accounts(0))
-      .build());
-      
+
+    let offer1 = Offer{offer_id: "0".to_string(), owner_id: accounts(1).into(), value: 10, token_id: None, ..Default::default()};
+    let offer2 = Offer{offer_id: "0".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None, ..Default::default()};
+    contract.insert_lending_offer("nft_collection_test1".to_string(), offer1);
+    contract.insert_lending_offer("nft_collection_test2".to_string(), offer2);
+    assert_eq!(contract.get_lending_offers_map(&"nft_collection_test1".to_string()).get(&"0".to_string()).unwrap().value, 10);
+    assert_eq!(contract.get_lending_offers_map(&"nft_collection_test2".to_string()).get(&"0".to_string()).unwrap().value, 20);
+    assert_eq!(contract.get_lending_offers_book(&"nft_collection_test1".to_string()).len(), 1);
+  }
+
+  #[test]
+  #[should_panic(expected = "Offer not found")]
+  fn test_remove_borrowing_offer_not_found() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.remove_borrowing_offer("nft_collection_test".to_string(), "0".to_string());
+  }
+
+  #[test]
+  fn test_draw_lending_offer() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
     let nft_collection_id = "nft_collection_test".to_string();
-    let offer = Offer{offer_id: "offer_id_test".to_string(), owner_id: accounts(1).into(), value: 10, token_id: None};
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("borrowing");
-    let mut borrowing_offers_empty_vec = Vector::new(vector_id.into_bytes().to_vec());
-    borrowing_offers_empty_vec.push(&offer);
-    contract.borrowing_offers_vecs.insert(&nft_collection_id, &borrowing_offers_empty_vec);
-    let result_borrowing_offers = contract.get_borrowing_offers_vec_from_nft_collection(nft_collection_id.clone());
-    assert_eq!(result_borrowing_offers.get(0).unwrap().offer_id, "offer_id_test");
-    assert_eq!(result_borrowing_offers.get(0).unwrap().owner_id, accounts(1).to_string());
-    assert_eq!(result_borrowing_offers.get(0).unwrap().value, 10);
+    let pool = Offer{max_value_per_loan: Some(20), ..sample_lending_offer("0", 50, 1000)};
+    contract.insert_lending_offer(nft_collection_id.clone(), pool);
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("1", 40, 1000));
+
+    // two borrowers draw from the pool, it keeps its place among offers of the same value
+    assert_eq!(contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 20).value, 20);
+    assert_eq!(contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 20).value, 20);
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("2", 10, 1000));
+    let book = contract.get_lending_offers_book(&nft_collection_id);
+    let offers = contract.get_book_offers(&book, &contract.get_lending_offers_map(&nft_collection_id));
+    let offer_ids: Vec<String> = offers.iter().map(|offer| offer.offer_id.clone()).collect();
+    assert_eq!(offer_ids, vec!["1".to_string(), "0".to_string(), "2".to_string()]);
+    assert_eq!(offers[1].value, 10);
+    assert_eq!(offers[1].loan_value_cap(), 10);
+
+    // the last draw uses the pool up
+    contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 10);
+    assert!(contract.get_lending_offers_map(&nft_collection_id).get(&"0".to_string()).is_none());
   }
 
+  #[test]
+  #[should_panic(expected = "The value is above the offer's maximum per loan")]
+  fn test_draw_lending_offer_above_max_value_per_loan() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    let pool = Offer{max_value_per_loan: Some(20), ..sample_lending_offer("0", 50, 1000)};
+    contract.insert_lending_offer(nft_collection_id.clone(), pool);
+    contract.draw_lending_offer(nft_collection_id, "0".to_string(), 25);
+  }
 
   #[test]
-  fn test_sort_order_lending_offer_vec() {
-    let mut context = get_context(accounts(1));
+  fn test_restore_lending_offer() {
+    let context = get_context(accounts(1));
     testing_env!(context.build());
     let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
-            
-    testing_env!(context
-      .storage_usage(env::storage_usage())
-      .attached_deposit(MINT_STORAGE_COST)
-      .predecessor_account_id(accounts(0))
-      .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("lending");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let offer = Offer{offer_id: "offer_id_test".to_string(), owner_id: accounts(1).into(), value: 10, token_id: None};
-
-    // test with empty vector
-    let ordered_offer_vec = contract.sort_order_lending_offer_vec(new_vec, offer);
-    assert_eq!(ordered_offer_vec.get(0).unwrap().value, 10);
-    
-    // test with a lower value
-    let offer2 = Offer{offer_id: "offer_id_test".to_string(), owner_id: accounts(1).into(), value: 5, token_id: None};
-    let ordered_offer_vec2 = contract.sort_order_lending_offer_vec(ordered_offer_vec, offer2);
-    assert_eq!(ordered_offer_vec2.get(0).unwrap().value, 5);
-    assert_eq!(ordered_offer_vec2.get(1).unwrap().value, 10);
-
-    //test with a higher value
-    let offer3 = Offer{offer_id: "offer_id_test".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None};
-    let ordered_offer_vec3 = contract.sort_order_lending_offer_vec(ordered_offer_vec2, offer3);
-    assert_eq!(ordered_offer_vec3.get(0).unwrap().value, 5);
-    assert_eq!(ordered_offer_vec3.get(1).unwrap().value, 10);
-    assert_eq!(ordered_offer_vec3.get(2).unwrap().value, 20);
-  } 
+    let pool = Offer{max_value_per_loan: Some(20), ..sample_lending_offer("0", 50, 1000)};
+    contract.insert_lending_offer(nft_collection_id.clone(), pool);
+    let first_draw = contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 20);
+    let second_draw = contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 20);
+
+    // a failed loan gives its value back to the pool
+    contract.restore_lending_offer(nft_collection_id.clone(), first_draw);
+    assert_eq!(contract.get_lending_offers_map(&nft_collection_id).get(&"0".to_string()).unwrap().value, 30);
+
+    // once the pool is used up the drawn value comes back as the offer itself
+    contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 20);
+    contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 10);
+    contract.restore_lending_offer(nft_collection_id.clone(), second_draw);
+    let restored_offer = contract.get_lending_offers_map(&nft_collection_id).get(&"0".to_string()).unwrap();
+    assert_eq!(restored_offer.value, 20);
+    assert_eq!(restored_offer.max_value_per_loan, Some(20));
+    assert_eq!(contract.get_lending_offers_book(&nft_collection_id).len(), 1);
+  }
 
   #[test]
-  fn test_sort_order_borrowing_offer_vec() {
-    let mut context = get_context(accounts(1));
+  fn test_match_offers_within_max_value_per_loan() {
+    let context = get_context(accounts(1));
     testing_env!(context.build());
     let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
-            
-    testing_env!(context
-      .storage_usage(env::storage_usage())
-      .attached_deposit(MINT_STORAGE_COST)
-      .predecessor_account_id(accounts(0))
-      .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("lending");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let offer = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 10, token_id: Some("token_id1".to_string())};
-
-    // test with empty vector
-    let ordered_offer_vec = contract.sort_order_borrowing_offer_vec(new_vec, offer);
-    assert_eq!(ordered_offer_vec.get(0).unwrap().value, 10);
-    
-    // test with a lower value
-    let offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 5, token_id: Some("token_id2".to_string())};
-    let ordered_offer_vec2 = contract.sort_order_borrowing_offer_vec(ordered_offer_vec, offer2);
-    assert_eq!(ordered_offer_vec2.get(0).unwrap().value, 10);
-    assert_eq!(ordered_offer_vec2.get(1).unwrap().value, 5);
-
-    //test with a higher value
-    let offer3 = Offer{offer_id: "offer_id_test3".to_string(), owner_id: accounts(1).into(), value: 20, token_id: Some("token_id3".to_string())};
-    let ordered_offer_vec3 = contract.sort_order_borrowing_offer_vec(ordered_offer_vec2, offer3);
-    assert_eq!(ordered_offer_vec3.get(0).unwrap().value, 20);
-    assert_eq!(ordered_offer_vec3.get(1).unwrap().value, 10);
-    assert_eq!(ordered_offer_vec3.get(2).unwrap().value, 5);
-  }
-
-}  
\ No newline at end of file
+    let pool = Offer{max_value_per_loan: Some(20), ..sample_lending_offer("0", 100, 1000)};
+    contract.insert_lending_offer(nft_collection_id.clone(), pool.clone());
+    assert!(contract.match_borrowing_offer(&nft_collection_id, &sample_borrowing_offer("0", 30, 1000)).is_none());
+    assert!(contract.match_borrowing_offer(&nft_collection_id, &sample_borrowing_offer("1", 20, 1000)).is_some());
+
+    for (offer_id, value) in [("0", 10), ("1", 20), ("2", 30)].iter() {
+      contract.insert_borrowing_offer(nft_collection_id.clone(), sample_borrowing_offer(offer_id, *value, 1000));
+    }
+    let matches = contract.match_lending_offer(&nft_collection_id, &pool);
+    let offer_ids: Vec<String> = matches.iter().map(|offer| offer.offer_id.clone()).collect();
+    assert_eq!(offer_ids, vec!["0".to_string(), "1".to_string()]);
+  }
+}
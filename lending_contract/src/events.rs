@@ -0,0 +1,36 @@
+use crate::*;
+
+// events follow the nep-297 format so indexers can pick them up
+
+const EVENT_STANDARD: &str = "nft_lending";
+const EVENT_VERSION: &str = "1.0.0";
+
+// logged when the note or receipt of a matched loan failed to mint, the loan is rolled back
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LoanOriginationFailed<'a> {
+  pub token_id: &'a str,
+  pub lender_id: &'a AccountId,
+  pub borrower_id: &'a AccountId,
+  pub warranty_collection: &'a AccountId,
+  pub warranty_token_id: &'a str,
+  pub loan_value: U128,
+  pub note_minted: bool,
+  pub receipt_minted: bool
+}
+
+impl LoanOriginationFailed<'_> {
+  pub fn emit(self) {
+    emit_event("loan_origination_failed", &self)
+  }
+}
+
+fn emit_event<T: Serialize>(event: &str, data: &T) {
+  let event_json = serde_json::json!({
+    "standard": EVENT_STANDARD,
+    "version": EVENT_VERSION,
+    "event": event,
+    "data": [data]
+  });
+  env::log(format!("EVENT_JSON:{}", event_json).as_bytes());
+}
@@ -0,0 +1,204 @@
+use crate::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LoanExtension {
+  pub borrower_id: AccountId,
+  pub expiration_time: u128,
+  // annual interest rate in basis points
+  pub apr: u128
+}
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // called by the receipt holder, the proposal only takes effect once the note holder approves it
+  pub fn propose_loan_extension(&mut self, token_id: TokenId, expiration_time: U128, apr: U128) -> Promise {
+    let loan = self.loans.get(&token_id).expect("Loan not found");
+    self.assert_active_loan(&loan);
+    let current_time = env::block_timestamp() as u128;
+    assert!(loan.expiration_time > current_time, "This loan has already expired");
+    assert!(expiration_time.0 > loan.expiration_time, "The new expiration time must be after the current one");
+    let collection_params = self.collection_params.get(&loan.warranty_collection).expect("This collection is not accepted as collateral");
+    assert!(
+      expiration_time.0 - current_time <= collection_params.max_loan_duration * NANOSECONDS_PER_SECOND,
+      "Loan duration is outside of the collection's allowed range"
+    );
+
+    ext_nft_contract::nft_token(
+      token_id.clone(),
+      &self.receipt_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    ).then(ext_self::resolve_loan_extension_proposal(
+      token_id,
+      env::predecessor_account_id(),
+      expiration_time,
+      apr,
+      &env::current_account_id(),
+      NO_DEPOSIT,
+      CALLBACK_GAS
+    ))
+  }
+
+  #[private]
+  pub fn resolve_loan_extension_proposal(&mut self, token_id: TokenId, borrower_id: AccountId, expiration_time: U128, apr: U128) -> bool {
+    if self.get_token_owner_from_promise(0) != Some(borrower_id.clone()) {
+      return false;
+    }
+    let loan_extension = LoanExtension {
+      borrower_id,
+      expiration_time: expiration_time.0,
+      apr: apr.0
+    };
+    self.loan_extensions.insert(&token_id, &loan_extension);
+    true
+  }
+
+  // called by the note contract once the note holder accepts the proposal, the interest accrued
+  // so far is settled from the borrower's balance and the loan restarts with the new terms
+  pub fn approve_loan_extension(&mut self, token_id: TokenId, note_owner_id: AccountId) -> Promise {
+    assert!(env::predecessor_account_id() == self.note_address, "Only note contract can call this function");
+    let loan_extension = self.loan_extensions.get(&token_id).expect("There is no extension proposal for this loan");
+    let mut loan = self.loans.get(&token_id).unwrap();
+    self.assert_active_loan(&loan);
+    let current_time = env::block_timestamp() as u128;
+    assert!(loan.expiration_time > current_time, "This loan has already expired");
+
+    let outstanding_balance = self.calculate_outstanding_balance(&loan);
+    let interest_due = outstanding_balance.saturating_sub(loan.value);
+    let borrower_balance = self.get_funds(loan_extension.borrower_id.clone(), &loan.currency);
+    assert!(borrower_balance >= interest_due, "Borrower doesn't have enough credit to settle the accrued interest");
+    self.set_funds(loan_extension.borrower_id, &loan.currency, borrower_balance - interest_due);
+
+    loan.value = outstanding_balance - interest_due;
+    loan.amount_repaid = 0;
+    loan.apr = loan_extension.apr;
+    loan.start_time = current_time;
+    loan.expiration_time = loan_extension.expiration_time;
+    self.loans.insert(&token_id, &loan);
+    self.loan_extensions.remove(&token_id);
+
+    ext_nft_contract::nft_update_loan_metadata(
+      token_id.clone(),
+      loan.value,
+      loan.expiration_time,
+      &self.receipt_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    );
+    ext_nft_contract::nft_update_loan_metadata(
+      token_id,
+      loan.value,
+      loan.expiration_time,
+      &self.note_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    );
+    let fee = self.charge_interest_fee(&loan.currency, interest_due);
+    self.pay_out(note_owner_id, &loan.currency, interest_due - fee)
+  }
+
+  pub fn get_loan_extension(&self, token_id: TokenId) -> Option<LoanExtension> {
+    self.loan_extensions.get(&token_id)
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::{MockedBlockchain, RuntimeFeesConfig, VMConfig};
+
+  use super::*;
+  use crate::loan::tests::sample_loan;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+
+  #[test]
+  fn test_resolve_loan_extension_proposal() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let token = Token{token_id: "0".to_string(), owner_id: accounts(4).into(), metadata: None, approved_account_ids: None, royalty: None};
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Successful(serde_json::to_vec(&Some(token)).unwrap())]
+    );
+    let success = contract.resolve_loan_extension_proposal("0".to_string(), accounts(4).into(), U128(10), U128(1500));
+    assert!(success);
+    assert_eq!(contract.get_loan_extension("0".to_string()).unwrap().apr, 1500);
+
+    let rejected = contract.resolve_loan_extension_proposal("1".to_string(), accounts(5).into(), U128(10), U128(1500));
+    assert!(!rejected);
+    assert!(contract.get_loan_extension("1".to_string()).is_none());
+  }
+
+  #[test]
+  fn test_approve_loan_extension() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // half a year in, 100 of interest is settled from the borrower's balance
+    testing_env!(context
+      .block_timestamp((YEAR_IN_SECONDS / 2 * NANOSECONDS_PER_SECOND) as u64)
+      .predecessor_account_id(accounts(2))
+      .build());
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    let new_expiration_time = 2 * YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND;
+    contract.loan_extensions.insert(&"0".to_string(), &LoanExtension{borrower_id: accounts(4).into(), expiration_time: new_expiration_time, apr: 1500});
+    contract.balances.insert(&accounts(4).into(), &(150));
+
+    contract.approve_loan_extension("0".to_string(), accounts(1).into());
+    let extended_loan = contract.loans.get(&"0".to_string()).unwrap();
+    assert_eq!(extended_loan.value, 1000);
+    assert_eq!(extended_loan.amount_repaid, 0);
+    assert_eq!(extended_loan.apr, 1500);
+    assert_eq!(extended_loan.expiration_time, new_expiration_time);
+    assert_eq!(extended_loan.start_time, YEAR_IN_SECONDS / 2 * NANOSECONDS_PER_SECOND);
+    assert!(contract.get_loan_extension("0".to_string()).is_none());
+    assert_eq!(contract.balances.get(&accounts(4).into()).unwrap(), 50);
+  }
+
+  #[test]
+  #[should_panic(expected = "Borrower doesn't have enough credit to settle the accrued interest")]
+  fn test_approve_loan_extension_without_credit() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .block_timestamp((YEAR_IN_SECONDS / 2 * NANOSECONDS_PER_SECOND) as u64)
+      .predecessor_account_id(accounts(2))
+      .build());
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    contract.loan_extensions.insert(&"0".to_string(), &LoanExtension{borrower_id: accounts(4).into(), expiration_time: 2 * YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, apr: 1500});
+    contract.approve_loan_extension("0".to_string(), accounts(1).into());
+  }
+
+  #[test]
+  #[should_panic(expected = "Only note contract can call this function")]
+  fn test_approve_loan_extension_not_note_contract() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .predecessor_account_id(accounts(1))
+      .build());
+    contract.approve_loan_extension("0".to_string(), accounts(1).into());
+  }
+}
@@ -0,0 +1,219 @@
+use crate::*;
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  pub fn add_whitelisted_ft(&mut self, ft_contract_id: AccountId) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    self.ft_whitelist.insert(&ft_contract_id);
+  }
+
+  pub fn remove_whitelisted_ft(&mut self, ft_contract_id: AccountId) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    self.ft_whitelist.remove(&ft_contract_id);
+  }
+
+  pub fn is_whitelisted_ft(&self, ft_contract_id: AccountId) -> bool {
+    self.ft_whitelist.contains(&ft_contract_id)
+  }
+
+  // a failed ft_transfer leaves the tokens in the contract, they are credited to the receiver
+  #[private]
+  pub fn resolve_ft_payout(&mut self, receiver_id: AccountId, ft_contract_id: AccountId, value: U128) -> bool {
+    if matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+      return true;
+    }
+    self.credit_funds(receiver_id, &Some(ft_contract_id), value.0);
+    false
+  }
+}
+
+//structure of message:
+/*
+function: string,
+args: {
+    argName: String
+}
+*/
+#[near_bindgen]
+impl FungibleTokenReceiver for LendingNftCollateral {
+
+  // the calling token contract is the currency, returns the amount that wasn't used
+  fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+    let ft_contract_id = env::predecessor_account_id();
+    assert!(self.ft_whitelist.contains(&ft_contract_id), "This token is not accepted");
+    let currency = Some(ft_contract_id);
+    let sender_id: AccountId = sender_id.into();
+    let parsed_message: Value = serde_json::from_str(&msg).expect("msg could not be parsed");
+
+    match parsed_message["function"].as_str().expect("msg could not be parsed") {
+      "post_lending_offer" => {
+        let nft_collection_id = parsed_message["args"]["nft_collection_id"].as_str().unwrap().to_string();
+        let apr = parsed_message["args"]["apr"].as_str().unwrap().parse().unwrap();
+        let loan_duration = parsed_message["args"]["loan_duration"].as_str().unwrap().parse().unwrap();
+        let max_value_per_loan = parsed_message["args"]["max_value_per_loan"].as_str().map(|value| value.parse().unwrap());
+        let expires_at = parsed_message["args"]["expires_at"].as_str().map(|expires_at| expires_at.parse().unwrap());
+        let target: Option<OfferTarget> = serde_json::from_value(parsed_message["args"]["target"].clone()).expect("msg could not be parsed");
+        if let Some(target) = &target {
+          assert_valid_target(target);
+        }
+        let lending_offer = Offer{owner_id: sender_id, value: amount.0, apr, loan_duration, max_value_per_loan, expires_at, target, currency, ..Default::default()};
+        self.place_lending_offer(nft_collection_id, lending_offer);
+        PromiseOrValue::Value(U128(0))
+      },
+      "pay_loan" => {
+        let token_id = parsed_message["args"]["token_id"].as_str().unwrap().to_string();
+        let loan = self.loans.get(&token_id).expect("Loan not found");
+        assert!(loan.currency == currency, "This loan isn't denominated in this token");
+        // the tokens are kept either way, whatever the payment doesn't use is credited to the
+        // payer's balance by the payment callback
+        self.request_loan_payment(token_id, &loan, sender_id, amount);
+        PromiseOrValue::Value(U128(0))
+      },
+      "bid_on_auction" => {
+        let token_id = parsed_message["args"]["token_id"].as_str().unwrap().to_string();
+        let auction = self.auctions.get(&token_id).expect("Auction not found");
+        assert!(auction.currency == currency, "This auction isn't denominated in this token");
+        let price = self.calculate_auction_price(&auction);
+        assert!(amount.0 >= price, "The amount sent doesn't cover the auction price");
+        self.settle_auction(token_id, auction, sender_id, price);
+        PromiseOrValue::Value(U128(amount.0 - price))
+      },
+      "deposit_balance" => {
+        self.credit_funds(sender_id, &currency, amount.0);
+        PromiseOrValue::Value(U128(0))
+      },
+      _ => panic!("msg could not be parsed")
+    }
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::{MockedBlockchain, RuntimeFeesConfig, VMConfig};
+
+  use super::*;
+  use crate::loan::tests::sample_loan;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  #[test]
+  fn test_ft_on_transfer_post_lending_offer() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()});
+    contract.add_whitelisted_ft(accounts(5).into());
+    testing_env!(context
+      .predecessor_account_id(accounts(5))
+      .build());
+    let msg = r#"{"function": "post_lending_offer", "args": {"nft_collection_id": "nft_collection_test", "apr": "1000", "loan_duration": "604800"}}"#;
+    contract.ft_on_transfer(accounts(4), U128(500), msg.to_string());
+    let offer = contract.lending_offers.get(&nft_collection_id).unwrap().get(&"0".to_string()).unwrap();
+    assert_eq!(offer.value, 500);
+    assert_eq!(offer.owner_id, accounts(4).to_string());
+    assert_eq!(offer.currency, Some(accounts(5).into()));
+  }
+
+  #[test]
+  fn test_ft_on_transfer_deposit_balance() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.add_whitelisted_ft(accounts(5).into());
+    testing_env!(context
+      .predecessor_account_id(accounts(5))
+      .build());
+    contract.ft_on_transfer(accounts(4), U128(500), r#"{"function": "deposit_balance"}"#.to_string());
+    assert_eq!(contract.get_ft_balance_value(accounts(4).into(), accounts(5).into()).0, 500);
+    assert_eq!(contract.get_balance_value(accounts(4).into()), 0);
+  }
+
+  #[test]
+  #[should_panic(expected = "This token is not accepted")]
+  fn test_ft_on_transfer_not_whitelisted() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .predecessor_account_id(accounts(5))
+      .build());
+    contract.ft_on_transfer(accounts(4), U128(500), r#"{"function": "deposit_balance"}"#.to_string());
+  }
+
+  #[test]
+  #[should_panic(expected = "This loan isn't denominated in this token")]
+  fn test_ft_on_transfer_pay_loan_wrong_token() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.add_whitelisted_ft(accounts(5).into());
+    contract.loans.insert(&"0".to_string(), &sample_loan(1000, 0));
+    testing_env!(context
+      .predecessor_account_id(accounts(5))
+      .build());
+    contract.ft_on_transfer(accounts(4), U128(500), r#"{"function": "pay_loan", "args": {"token_id": "0"}}"#.to_string());
+  }
+
+  #[test]
+  fn test_ft_on_transfer_pay_loan() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.add_whitelisted_ft(accounts(5).into());
+    contract.loans.insert(&"0".to_string(), &Loan{currency: Some(accounts(5).into()), expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 0)});
+    testing_env!(context
+      .predecessor_account_id(accounts(5))
+      .build());
+    let unused_amount = match contract.ft_on_transfer(accounts(4), U128(500), r#"{"function": "pay_loan", "args": {"token_id": "0"}}"#.to_string()) {
+      PromiseOrValue::Value(value) => value,
+      PromiseOrValue::Promise(_) => panic!("The payment should be kept by the contract")
+    };
+    assert_eq!(unused_amount, U128(0));
+
+    // a rejected payment is credited to the payer only once
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Failed, PromiseResult::Failed]
+    );
+    assert!(!contract.resolve_loan_payment("0".to_string(), accounts(4).into(), U128(500)));
+    assert_eq!(contract.get_ft_balance_value(accounts(4).into(), accounts(5).into()).0, 500);
+  }
+
+  #[test]
+  fn test_resolve_ft_payout_failed() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Failed]
+    );
+    let success = contract.resolve_ft_payout(accounts(4).into(), accounts(5).into(), U128(300));
+    assert!(!success);
+    assert_eq!(contract.get_ft_balance_value(accounts(4).into(), accounts(5).into()).0, 300);
+  }
+}
@@ -1,5 +1,8 @@
 use near_sdk::json_types::U128;
-use near_sdk::AccountId;
+use near_sdk::{AccountId, Promise};
+use crate::Offer;
+use crate::order_book::PriceLevel;
+use crate::collection::CollectionParams;
 
 pub type TokenId = String;
 
@@ -28,18 +31,22 @@ pub trait NftLending {
     fn borrow_offer_to_specific_request(&mut self, nft_collection_id: AccountId, collateral_nft: TokenId, offer_id: U128) -> Option<U128>;
 
     //marketplace view functions
-    fn get_loan_offers(&self, nft_collection_id: AccountId, start_index: U128, pagination: U128) -> Vec<U128>;
+    fn get_loan_offers(&self, nft_collection_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<Offer>;
 
-    fn get_borrow_offers(&self, nft_collection_id: AccountId, start_index: U128, pagination: U128) -> Vec<U128>;
+    fn get_borrow_offers(&self, nft_collection_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<Offer>;
+
+    fn get_loan_offers_depth(&self, nft_collection_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<PriceLevel>;
+
+    fn get_borrow_offers_depth(&self, nft_collection_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<PriceLevel>;
 
     //governance functions
-    fn add_collection(&mut self, nft_collection_id: AccountId, apy_rate: U128) -> bool;
+    fn add_collection(&mut self, nft_collection_id: AccountId, collection_params: CollectionParams);
 
-    fn remove_collection(&mut self, nft_collection_id: AccountId, apy_rate: U128) -> bool;
+    fn remove_collection(&mut self, nft_collection_id: AccountId);
 
-    fn alter_collection(&mut self, nft_collection_id: AccountId, apy_rate: U128) -> bool;
+    fn alter_collection(&mut self, nft_collection_id: AccountId, collection_params: CollectionParams);
 
-    fn retrieve_funds(&mut self) -> bool;
+    fn retrieve_funds(&mut self, currency: Option<AccountId>, value: U128) -> Promise;
 // TODO return struct
     fn get_contract_params(&self) -> bool;
 
@@ -1,6 +1,6 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::{near_bindgen, AccountId};
-use near_sdk::collections::{LookupMap, Vector};
+use near_sdk::collections::{LookupMap, LookupSet, TreeMap, UnorderedSet};
 use near_sdk::json_types::{U128, ValidAccountId};
 use near_sdk::env;
 use near_sdk::serde::{Deserialize, Serialize};
@@ -11,19 +11,55 @@ use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
 use near_sdk::callback;
 use near_sdk::ext_contract;
 use near_sdk::serde_json::{self, Value};
-use near_sdk::{Balance, Gas, Promise, PromiseOrValue};
+use near_sdk::{Balance, Gas, Promise, PromiseOrValue, PromiseResult};
 
 // use crate::lending_contract_interface::NftLending;
 
 pub type NftCollection = AccountId;
 const NO_DEPOSIT: Balance = 0;
+const ONE_YOCTO: Balance = 1;
 const BASE_GAS: Gas = 5_000_000_000_000;
+const CALLBACK_GAS: Gas = 50_000_000_000_000;
+const FT_TRANSFER_GAS: Gas = 10_000_000_000_000;
+// the custody callback can pay out the lender's excess, mints the note and receipt and schedules their callback
+const CUSTODY_CALLBACK_GAS: Gas = 100_000_000_000_000;
+// the refinance callback mints the new tokens and schedules its own callback
+const REFINANCE_CALLBACK_GAS: Gas = 100_000_000_000_000;
+// posting a borrowing offer from the traits callback can schedule a custody check and its callback
+const TRAITS_CALLBACK_GAS: Gas = 150_000_000_000_000;
+const BASIS_POINTS: u128 = 10_000;
+const YEAR_IN_SECONDS: u128 = 31_536_000;
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
 
 mod lending_contract_interface;
 pub mod nft_on_impl;
 pub mod loan;
 pub mod balance;
 pub mod controller;
+pub mod collection;
+pub mod events;
+pub mod extension;
+pub mod refinance;
+pub mod auction;
+pub mod bundle;
+pub mod fungible_token;
+pub mod treasury;
+pub mod loan_index;
+pub mod order_book;
+pub mod amend;
+pub mod offer_index;
+pub mod targeting;
+pub mod oracle;
+
+use crate::collection::CollectionParams;
+use crate::events::LoanOriginationFailed;
+use crate::extension::LoanExtension;
+use crate::auction::Auction;
+use crate::bundle::Collateral;
+use crate::loan::{LoanStatus, LoanView};
+use crate::amend::OfferSide;
+use crate::targeting::{assert_valid_target, OfferTarget, TokenTrait};
+use crate::oracle::FloorPrice;
 
 #[ext_contract(ext_nft_contract)]
 trait NftContract {
@@ -44,6 +80,83 @@ trait NftContract {
       approval_id: Option<u128>,
       memo: Option<String>
     );
+
+    fn nft_update_loan_metadata(&self,
+      token_id: TokenId,
+      loan_value: u128,
+      loan_expiration_time: u128);
+}
+
+#[ext_contract(ext_oracle)]
+trait FloorPriceOracle {
+    fn get_floor_price(&self,
+      nft_collection_id: NftCollection,
+      currency: Option<AccountId>) -> Option<FloorPrice>;
+}
+
+#[ext_contract(ext_ft_contract)]
+trait FtContract {
+    fn ft_transfer(&mut self,
+      receiver_id: AccountId,
+      amount: U128,
+      memo: Option<String>);
+}
+
+#[ext_contract(ext_self)]
+trait LendingCallbacks {
+    fn resolve_collateral_custody(&mut self,
+      nft_collection_id: NftCollection,
+      lending_offer: Offer,
+      borrowing_offer: Offer,
+      restore_lending_offer: bool) -> bool;
+
+    fn resolve_loan_origination(&mut self,
+      token_id: TokenId,
+      lender_account_id: AccountId,
+      borrower_account_id: AccountId) -> bool;
+
+    fn resolve_loan_payment(&mut self,
+      token_id: TokenId,
+      payer_id: AccountId,
+      payment_value: U128) -> bool;
+
+    fn resolve_loan_extension_proposal(&mut self,
+      token_id: TokenId,
+      borrower_id: AccountId,
+      expiration_time: U128,
+      apr: U128) -> bool;
+
+    fn resolve_refinance_owners(&mut self,
+      token_id: TokenId,
+      borrower_id: AccountId,
+      lending_offer: Offer) -> bool;
+
+    fn resolve_refinance_origination(&mut self,
+      token_id: TokenId,
+      new_token_id: TokenId,
+      loan_owners: (AccountId, AccountId),
+      lending_offer: Offer) -> bool;
+
+    fn resolve_liquidation(&mut self,
+      token_id: TokenId,
+      note_owner_id: AccountId) -> bool;
+
+    fn resolve_collateral_traits(&mut self,
+      nft_collection_id: NftCollection,
+      borrowing_offer: Offer) -> bool;
+
+    fn resolve_floor_price(&mut self,
+      nft_collection_id: NftCollection,
+      currency: Option<AccountId>) -> bool;
+
+    fn resolve_ft_payout(&mut self,
+      receiver_id: AccountId,
+      ft_contract_id: AccountId,
+      value: U128) -> bool;
+
+    fn resolve_treasury_withdrawal(&mut self,
+      ft_contract_id: AccountId,
+      value: U128) -> bool;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default, BorshDeserialize, BorshSerialize)]
@@ -52,7 +165,25 @@ pub struct Offer {
   pub offer_id: String,
   pub owner_id: AccountId,
   pub value: u128,
-  pub token_id: Option<TokenId>
+  pub token_id: Option<TokenId>,
+  // annual interest rate in basis points, for borrowing offers it's the maximum accepted
+  pub apr: u128,
+  // requested loan duration in seconds
+  pub loan_duration: u128,
+  // for borrowing offers backed by a bundle, the NFTs deposited along with token_id
+  pub bundled_collateral: Vec<Collateral>,
+  // fungible token contract the loan is denominated in, None for NEAR
+  pub currency: Option<AccountId>,
+  // lending offers can fund several loans, value is then the capacity left and this caps each loan
+  pub max_value_per_loan: Option<u128>,
+  // block timestamp in nanoseconds after which the offer can't be matched and anyone can prune it
+  pub expires_at: Option<u128>,
+  // lending offers limited to some tokens of the collection, None bids on the whole collection
+  pub target: Option<OfferTarget>,
+  // for borrowing offers, the collateral's traits as read from its metadata when it was posted
+  pub traits: Vec<TokenTrait>,
+  // arrival order in the book, breaks ties between offers of the same value
+  pub sequence: u64
 }
 
 #[near_bindgen]
@@ -68,19 +199,48 @@ pub struct LendingNftCollateral {
   pub current_lending_offer_id: LookupMap<NftCollection, u128>,
   pub current_borrowing_offer_id: LookupMap<NftCollection, u128>,
 
-  //ordered offers
-  //lower(0) to higher(len-1)
-  pub lending_offers_vecs: LookupMap<NftCollection, Vector<Offer>>,
-  //higher(0)  to lower(len-1)
-  pub borrowing_offers_vecs: LookupMap<NftCollection, Vector<Offer>>,
+  //ordered offer ids, the first key is the best offer
+  //lending: higher value first, borrowing: lower value first
+  pub lending_offers_books: LookupMap<NftCollection, TreeMap<(u128, u64), String>>,
+  pub borrowing_offers_books: LookupMap<NftCollection, TreeMap<(u128, u64), String>>,
+  //targeted lending offers, ordered like the lending book and matched before it
+  pub targeted_lending_offers_books: LookupMap<NftCollection, TreeMap<(u128, u64), String>>,
+  pub offer_sequence: u64,
+  // (collection, book, offer id) of every resting offer by owner
+  pub offers_by_account: LookupMap<AccountId, UnorderedSet<(NftCollection, OfferSide, String)>>,
 
   pub token_id_counter: u128,
   pub loans: LookupMap<TokenId, Loan>,
-  pub loan_expiration_seconds_limit: u128,
+  // token ids by current note holder, receipt holder and warranty collection (active loans only)
+  pub loans_by_lender: LookupMap<AccountId, UnorderedSet<TokenId>>,
+  pub loans_by_borrower: LookupMap<AccountId, UnorderedSet<TokenId>>,
+  pub loans_by_collection: LookupMap<NftCollection, UnorderedSet<TokenId>>,
+  // (note holder, receipt holder) while the loan's tokens exist
+  pub loan_owners: LookupMap<TokenId, (AccountId, AccountId)>,
+  pub collection_params: LookupMap<NftCollection, CollectionParams>,
+  pub loan_extensions: LookupMap<TokenId, LoanExtension>,
+  pub auctions: LookupMap<TokenId, Auction>,
+  pub pending_bundles: LookupMap<AccountId, Vec<Collateral>>,
+  // every NFT held for a borrowing offer, a pending bundle or a loan, each one can only back one of them
+  pub committed_collateral: LookupSet<(NftCollection, TokenId)>,
   pub note_address: AccountId,
   pub receipt_address: AccountId,
 
-  pub balances: LookupMap<AccountId, u128>
+  pub balances: LookupMap<AccountId, u128>,
+  pub ft_whitelist: LookupSet<AccountId>,
+  // keyed by (owner, fungible token contract)
+  pub ft_balances: LookupMap<(AccountId, AccountId), u128>,
+
+  // protocol fees in basis points and the fees collected by currency, kept apart from balances
+  pub origination_fee: u128,
+  pub interest_fee: u128,
+  pub treasury: LookupMap<Option<AccountId>, u128>,
+
+  // floor prices reported by the oracle by (collection, currency), older than the maximum age in
+  // seconds they can't back a loan
+  pub oracle_id: Option<AccountId>,
+  pub floor_price_max_age: u128,
+  pub floor_prices: LookupMap<(NftCollection, Option<AccountId>), FloorPrice>
 }
 
 impl Default for LendingNftCollateral {
@@ -93,9 +253,18 @@ impl Default for LendingNftCollateral {
 #[serde(crate = "near_sdk::serde")]
 pub struct Loan {
   pub value: u128,
+  // annual interest rate in basis points
+  pub apr: u128,
+  pub start_time: u128,
   pub expiration_time: u128,
+  pub amount_repaid: u128,
   pub warranty_collection: AccountId,
-  pub warranty_token_id: String
+  pub warranty_token_id: String,
+  // the rest of the bundle when the loan is backed by more than one NFT
+  pub bundled_collateral: Vec<Collateral>,
+  // fungible token contract the loan is denominated in, None for NEAR
+  pub currency: Option<AccountId>,
+  pub status: LoanStatus
 }
 
 // impl NftLending for LendingNftCollateral{
@@ -107,137 +276,176 @@ impl LendingNftCollateral {
     Self {
       token_id_counter: 0,
       // mudar depois: colocar validação no código
-      lending_offers_quantity_limit: 20,
-      borrowing_offers_quantity_limit: 20,
+      lending_offers_quantity_limit: 5000,
+      borrowing_offers_quantity_limit: 5000,
       owner_id: owner_id,
       borrowing_offers: LookupMap::new(b"borrowing_offers".to_vec()),
       lending_offers: LookupMap::new(b"lending_offers".to_vec()),
       current_lending_offer_id: LookupMap::new(b"current_lending_offer_id".to_vec()),
       current_borrowing_offer_id: LookupMap::new(b"current_lending_offer_id".to_vec()),
-      lending_offers_vecs: LookupMap::new(b"lending_offers_vecs".to_vec()),
-      borrowing_offers_vecs: LookupMap::new(b"borrowing_offers_vecs".to_vec()),
+      lending_offers_books: LookupMap::new(b"lending_offers_books".to_vec()),
+      borrowing_offers_books: LookupMap::new(b"borrowing_offers_books".to_vec()),
+      targeted_lending_offers_books: LookupMap::new(b"targeted_lending_offers_books".to_vec()),
+      offer_sequence: 0,
+      offers_by_account: LookupMap::new(b"offers_by_account".to_vec()),
       loans: LookupMap::new(b"loans".to_vec()),
-      // 15 days in seconds
-      loan_expiration_seconds_limit: 1296000,
+      loans_by_lender: LookupMap::new(b"loans_by_lender".to_vec()),
+      loans_by_borrower: LookupMap::new(b"loans_by_borrower".to_vec()),
+      loans_by_collection: LookupMap::new(b"loans_by_collection".to_vec()),
+      loan_owners: LookupMap::new(b"loan_owners".to_vec()),
+      collection_params: LookupMap::new(b"collection_params".to_vec()),
+      loan_extensions: LookupMap::new(b"loan_extensions".to_vec()),
+      auctions: LookupMap::new(b"auctions".to_vec()),
+      pending_bundles: LookupMap::new(b"pending_bundles".to_vec()),
+      committed_collateral: LookupSet::new(b"committed_collateral".to_vec()),
       note_address: note_address,
       receipt_address: receipt_address,
       balances: LookupMap::new(b"balances".to_vec()),
+      ft_whitelist: LookupSet::new(b"ft_whitelist".to_vec()),
+      ft_balances: LookupMap::new(b"ft_balances".to_vec()),
+      origination_fee: 0,
+      interest_fee: 0,
+      treasury: LookupMap::new(b"treasury".to_vec()),
+      oracle_id: None,
+      floor_price_max_age: 0,
+      floor_prices: LookupMap::new(b"floor_prices".to_vec()),
     }
   }
 
-  fn get_best_lending_offer(&mut self, nft_collection_id: NftCollection) -> Option<Offer> 
-  {
-    let lending_offer_vec = self.get_lending_offers_vec_from_nft_collection(nft_collection_id.to_string());
-    let best_offer_index = if lending_offer_vec.len() == 0 {0} else {lending_offer_vec.len() - 1};
-    lending_offer_vec.get(best_offer_index)
+  // expired offers waiting to be pruned are passed over
+  fn get_best_lending_offer(&self, nft_collection_id: NftCollection) -> Option<Offer> {
+    let offer_map = self.get_lending_offers_map(&nft_collection_id);
+    self.get_lending_offers_book(&nft_collection_id).iter()
+      .filter_map(|(_, offer_id)| offer_map.get(&offer_id))
+      .find(|offer| !offer.is_expired())
   }
 
-  fn get_best_borrowing_offer(&mut self, nft_collection_id: NftCollection) -> Option<Offer> {
-    let borrowing_offer_vec = self.get_borrowing_offers_vec_from_nft_collection(nft_collection_id.to_string());
-    let best_offer_index = if borrowing_offer_vec.len() == 0 {0} else {borrowing_offer_vec.len() - 1};
-    borrowing_offer_vec.get(best_offer_index)
+  fn get_best_borrowing_offer(&self, nft_collection_id: NftCollection) -> Option<Offer> {
+    let offer_map = self.get_borrowing_offers_map(&nft_collection_id);
+    self.get_borrowing_offers_book(&nft_collection_id).iter()
+      .filter_map(|(_, offer_id)| offer_map.get(&offer_id))
+      .find(|offer| !offer.is_expired())
   }
 
-  fn cancel_specific_lending_offer(&mut self, offer_id: String, nft_collection_id: NftCollection) {
-    let nft_collection_lending_offers = self.lending_offers.get(&nft_collection_id);
-    let mut nft_collection_lending_offer_vec = self.lending_offers_vecs.get(&nft_collection_id).unwrap();
-    let specific_lending_offer = nft_collection_lending_offers.unwrap().get(&offer_id).unwrap();
+  pub fn cancel_specific_lending_offer(&mut self, offer_id: String, nft_collection_id: NftCollection) -> Promise {
+    let specific_lending_offer = self.remove_lending_offer(nft_collection_id, offer_id);
     assert!(env::predecessor_account_id() == specific_lending_offer.owner_id, "You are not the owner of this offer");
-    // reorder and remove from vecs
-    self.reorder_vec_without_specific_offer(&mut nft_collection_lending_offer_vec, specific_lending_offer.clone());
-    self.lending_offers.get(&nft_collection_id.clone()).unwrap().remove(&offer_id);
+
+    // release escrowed funds
+    self.pay_out(specific_lending_offer.owner_id, &specific_lending_offer.currency, specific_lending_offer.value)
   }
 
-  fn cancel_specific_borrowing_offer(&mut self, offer_id: String, nft_collection_id: NftCollection) -> Promise {
-    let nft_collection_borrowing_offers = self.borrowing_offers.get(&nft_collection_id);
-    let mut nft_collection_borrowing_offer_vec = self.borrowing_offers_vecs.get(&nft_collection_id).unwrap();
-    let specific_borrowing_offer = nft_collection_borrowing_offers.unwrap().get(&offer_id).unwrap();
+  pub fn cancel_specific_borrowing_offer(&mut self, offer_id: String, nft_collection_id: NftCollection) -> Promise {
+    let specific_borrowing_offer = self.remove_borrowing_offer(nft_collection_id.clone(), offer_id);
     assert!(env::predecessor_account_id() == specific_borrowing_offer.owner_id, "You are not the owner of this offer");
-    // REORDER AND REMOVE FROM VECS
-    self.reorder_vec_without_specific_offer(&mut nft_collection_borrowing_offer_vec, specific_borrowing_offer.clone());
-    self.borrowing_offers.get(&nft_collection_id.clone()).unwrap().remove(&offer_id);
       
     //transfer nft back
-    ext_nft_contract::nft_transfer(
-      env::current_account_id(), 
-      specific_borrowing_offer.token_id.unwrap(),
-      None,
-      None,
-      &specific_borrowing_offer.owner_id,
-      NO_DEPOSIT,
-      BASE_GAS
-    )
+    let collateral = specific_borrowing_offer.collateral(&nft_collection_id);
+    self.transfer_collateral(specific_borrowing_offer.owner_id, collateral)
   }
 
-  fn choose_specific_lending_offer(&mut self, nft_collection_id: NftCollection, offer_id: String, token_id: TokenId) -> bool {
-    let nft_collection_lending_offers = self.lending_offers.get(&nft_collection_id);
-    let mut nft_collection_lending_offer_vec = self.lending_offers_vecs.get(&nft_collection_id).unwrap();
-    let specific_lending_offer = nft_collection_lending_offers.unwrap().get(&offer_id).unwrap();
-    self.post_loan(specific_lending_offer.clone().owner_id, env::predecessor_account_id(), nft_collection_id.clone(), token_id, U128(specific_lending_offer.clone().value));
-    // REORDER AND REMOVE FROM VECS
-    self.reorder_vec_without_specific_offer(&mut nft_collection_lending_offer_vec, specific_lending_offer.clone());
-    self.lending_offers.get(&nft_collection_id.clone()).unwrap().remove(&offer_id);
+  // the borrower takes as much as the offer lends in a single loan. The NFT's traits aren't read
+  // here, so offers targeting traits can only be matched through the books
+  pub fn choose_specific_lending_offer(&mut self, nft_collection_id: NftCollection, offer_id: String, token_id: TokenId) -> bool {
+    let specific_lending_offer = self.get_lending_offers_map(&nft_collection_id).get(&offer_id).expect("Offer not found");
+    let loan_value = specific_lending_offer.loan_value_cap();
+    self.assert_collection_enabled(&nft_collection_id);
+    self.assert_valid_loan_value(&nft_collection_id, loan_value);
+    let borrowing_offer = Offer{owner_id: env::predecessor_account_id(), value: loan_value, token_id: Some(token_id), currency: specific_lending_offer.currency.clone(), ..Default::default()};
+    assert!(specific_lending_offer.accepts(&borrowing_offer), "This offer doesn't lend against this NFT");
+    let specific_lending_offer = self.draw_lending_offer(nft_collection_id.clone(), offer_id, loan_value);
+    // the NFT is claimed right away so it can't back another loan while its custody is checked
+    self.commit_collateral(&borrowing_offer.collateral(&nft_collection_id));
+    self.post_loan(nft_collection_id, specific_lending_offer, borrowing_offer, true);
     true
   }
 
-  fn choose_specific_borrowing_offer(&mut self, nft_collection_id: NftCollection, offer_id: String) -> bool {
-    let nft_collection_borrowing_offers = self.borrowing_offers.get(&nft_collection_id);
-    let mut nft_collection_borrowing_offer_vec = self.borrowing_offers_vecs.get(&nft_collection_id).unwrap();
-    let specific_borrowing_offer = nft_collection_borrowing_offers.unwrap().get(&offer_id).unwrap();
-    self.post_loan(env::predecessor_account_id(), specific_borrowing_offer.clone().owner_id, nft_collection_id.clone(), specific_borrowing_offer.clone().token_id.unwrap(), U128(specific_borrowing_offer.clone().value));
-    // REORDER AND REMOVE FROM VECS
-    self.reorder_vec_without_specific_offer(&mut nft_collection_borrowing_offer_vec, specific_borrowing_offer.clone());
-    self.borrowing_offers.get(&nft_collection_id.clone()).unwrap().remove(&offer_id);
+  #[payable]
+  pub fn choose_specific_borrowing_offer(&mut self, nft_collection_id: NftCollection, offer_id: String) -> bool {
+    let specific_borrowing_offer = self.remove_borrowing_offer(nft_collection_id.clone(), offer_id);
+    assert!(!specific_borrowing_offer.is_expired(), "This offer has expired");
+    self.assert_collection_enabled(&nft_collection_id);
+    assert!(specific_borrowing_offer.currency.is_none(), "This offer can only be matched by a lending offer in its currency");
+    self.lock_funds(env::predecessor_account_id(), specific_borrowing_offer.value);
+    // the lender takes the borrower's terms
+    let lending_offer = Offer{owner_id: env::predecessor_account_id(), value: specific_borrowing_offer.value, apr: specific_borrowing_offer.apr, loan_duration: specific_borrowing_offer.loan_duration, ..Default::default()};
+    self.post_loan(nft_collection_id, lending_offer, specific_borrowing_offer, false);
     true
   }
 
   #[payable]
-  fn post_lending_offer(&mut self, nft_collection_id: AccountId, value_offered: U128) -> bool {
-    let mut lending_offers_vec = self.get_lending_offers_vec_from_nft_collection(nft_collection_id.clone());
-    assert!(lending_offers_vec.len() < self.lending_offers_quantity_limit, "There are too many offers already");
-
-    if self.evaluate_lending_offer_possible_match(&nft_collection_id, value_offered) {
-      let best_borrowing_offer = self.get_best_borrowing_offer(nft_collection_id.clone()).unwrap();
-      self.post_loan(env::predecessor_account_id(), best_borrowing_offer.owner_id, nft_collection_id.clone(), best_borrowing_offer.token_id.unwrap(), value_offered);
-      self.borrowing_offers_vecs.get(&nft_collection_id.clone()).unwrap().pop();
-      false
-    }
-    else {
-      let offer_id = self.current_lending_offer_id.get(&nft_collection_id).unwrap_or(0);
-      let offer = Offer{offer_id: offer_id.to_string(), owner_id: env::predecessor_account_id(), value: value_offered.0, token_id: None};
-      let ordered_lending_offer_vec = self.sort_order_lending_offer_vec(lending_offers_vec, offer.clone());
-      self.lending_offers_vecs.insert(&nft_collection_id.clone(), &ordered_lending_offer_vec);
-      let mut offer_map = LookupMap::new(b"lending_offer".to_vec());
-      offer_map.insert(&offer_id.to_string(), &offer);
-      self.lending_offers.insert(&nft_collection_id.clone(), &offer_map);
-      self.current_lending_offer_id.insert(&nft_collection_id.clone(), &(offer_id + 1));
-      true
+  pub fn post_lending_offer(&mut self, nft_collection_id: AccountId, value_offered: U128, apr: U128, loan_duration: U128, max_value_per_loan: Option<U128>, expires_at: Option<U128>) -> bool {
+    self.lock_funds(env::predecessor_account_id(), value_offered.0);
+    let lending_offer = Offer{owner_id: env::predecessor_account_id(), value: value_offered.0, apr: apr.0, loan_duration: loan_duration.0, max_value_per_loan: max_value_per_loan.map(|value| value.0), expires_at: expires_at.map(|timestamp| timestamp.0), ..Default::default()};
+    self.place_lending_offer(nft_collection_id, lending_offer)
+  }
+
+  // the offer's value is already escrowed, in NEAR or in the fungible token given as currency
+  fn place_lending_offer(&mut self, nft_collection_id: AccountId, lending_offer: Offer) -> bool {
+    assert!(self.get_lending_book_of(&nft_collection_id, &lending_offer).len() < self.lending_offers_quantity_limit, "There are too many offers already");
+    self.assert_valid_offer_terms(&nft_collection_id, &lending_offer);
+    assert!(!lending_offer.is_expired(), "The offer would already be expired");
+
+    // whatever isn't lent rests in the book
+    let mut lending_offer = self.fill_lending_offer(nft_collection_id.clone(), lending_offer);
+    if lending_offer.value == 0 {
+      return false;
     }
+
+    let offer_id = self.current_lending_offer_id.get(&nft_collection_id).unwrap_or(0);
+    lending_offer.offer_id = offer_id.to_string();
+    self.insert_lending_offer(nft_collection_id.clone(), lending_offer);
+    self.current_lending_offer_id.insert(&nft_collection_id.clone(), &(offer_id + 1));
+    true
   }
 
-  #[payable]
-  fn post_borrowing_offer(&mut self, nft_collection_id: NftCollection, value_offered: U128, collateral_nft: TokenId, nft_owner_id: AccountId) -> bool {
-    let mut borrowing_offers_vec = self.get_borrowing_offers_vec_from_nft_collection(nft_collection_id.clone());
-    assert!(borrowing_offers_vec.len() < self.borrowing_offers_quantity_limit, "There are too many offers already");
-
-    //check if there is a match
-    if self.evaluate_borrowing_offer_possible_match(&nft_collection_id, value_offered) {
-      let best_lending_offer = self.get_best_lending_offer(nft_collection_id.clone()).unwrap();
-      self.post_loan(best_lending_offer.owner_id, nft_owner_id, nft_collection_id.clone(), collateral_nft, value_offered);
-      self.lending_offers_vecs.get(&nft_collection_id.clone()).unwrap().pop();
-      false
+  fn assert_valid_borrowing_offer(&self, nft_collection_id: &NftCollection, borrowing_offer: &Offer) {
+    assert!(self.get_borrowing_offers_book(nft_collection_id).len() < self.borrowing_offers_quantity_limit, "There are too many offers already");
+    self.assert_valid_offer_terms(nft_collection_id, borrowing_offer);
+    // a bundle is checked against the limit of all its NFTs when it's posted
+    if borrowing_offer.bundled_collateral.is_empty() {
+      self.assert_valid_loan_value(nft_collection_id, borrowing_offer.value);
+    }
+    if let Some(ft_contract_id) = &borrowing_offer.currency {
+      assert!(self.ft_whitelist.contains(ft_contract_id), "This token is not accepted");
     }
-    else {
-      let offer_id = self.current_borrowing_offer_id.get(&nft_collection_id).unwrap_or(0);
-      let offer = Offer{offer_id: offer_id.to_string(), owner_id: nft_owner_id, value: value_offered.0, token_id: Some(collateral_nft)};
-      let ordered_borrowing_offer_vec = self.sort_order_lending_offer_vec(borrowing_offers_vec, offer.clone());
-      self.borrowing_offers_vecs.insert(&nft_collection_id.clone(), &ordered_borrowing_offer_vec);
-      let mut offer_map = LookupMap::new(b"borrowing_offer".to_vec());
-      offer_map.insert(&offer_id.to_string(), &offer);
-      self.borrowing_offers.insert(&nft_collection_id.clone(), &offer_map);
-      self.current_borrowing_offer_id.insert(&nft_collection_id.clone(), &(offer_id + 1));
-      true
+    assert!(!borrowing_offer.is_expired(), "The offer would already be expired");
+  }
+
+  // the same checks without panicking, for callbacks that already hold the collateral
+  fn is_valid_borrowing_offer(&self, nft_collection_id: &NftCollection, borrowing_offer: &Offer) -> bool {
+    let collection_params = match self.collection_params.get(nft_collection_id) {
+      Some(collection_params) => collection_params,
+      None => return false
+    };
+    let accepted_currency = match &borrowing_offer.currency {
+      Some(ft_contract_id) => self.ft_whitelist.contains(ft_contract_id),
+      None => true
+    };
+    self.get_borrowing_offers_book(nft_collection_id).len() < self.borrowing_offers_quantity_limit
+      && collection_params.enabled
+      && borrowing_offer.loan_duration >= collection_params.min_loan_duration
+      && borrowing_offer.loan_duration <= collection_params.max_loan_duration
+      && borrowing_offer.apr >= collection_params.min_apr
+      && borrowing_offer.apr <= collection_params.max_apr
+      && (!borrowing_offer.bundled_collateral.is_empty() || borrowing_offer.value <= collection_params.max_loan_value)
+      && accepted_currency
+      && !borrowing_offer.is_expired()
+  }
+
+  // the collateral is already escrowed
+  fn place_borrowing_offer(&mut self, nft_collection_id: NftCollection, borrowing_offer: Offer) -> bool {
+    self.assert_valid_borrowing_offer(&nft_collection_id, &borrowing_offer);
+    let mut borrowing_offer = borrowing_offer;
+    if self.fill_borrowing_offer(nft_collection_id.clone(), &borrowing_offer) {
+      return false;
     }
+
+    let offer_id = self.current_borrowing_offer_id.get(&nft_collection_id).unwrap_or(0);
+    borrowing_offer.offer_id = offer_id.to_string();
+    self.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer);
+    self.current_borrowing_offer_id.insert(&nft_collection_id.clone(), &(offer_id + 1));
+    true
   }
 }
 
@@ -283,14 +491,10 @@ mod tests {
       .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("lending");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let lending_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 10, token_id: None};
-    let lending_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None};
-    new_vec.push(&lending_offer1);
-    new_vec.push(&lending_offer2);
-    contract.lending_offers_vecs.insert(&nft_collection_id, &new_vec);
+    let lending_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 10, token_id: None, ..Default::default()};
+    let lending_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None, ..Default::default()};
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer1);
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer2);
     let best_offer = contract.get_best_lending_offer(nft_collection_id.clone()).unwrap();
     assert_eq!(best_offer.value, 20);
     assert_eq!(best_offer.offer_id, "offer_id_test2".to_string());
@@ -309,14 +513,10 @@ mod tests {
       .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("borrowing");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let borrowing_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 20, token_id: Some("token_id_test1".to_string())};
-    let borrowing_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 10, token_id: Some("token_id_test2".to_string())};
-    new_vec.push(&borrowing_offer1);
-    new_vec.push(&borrowing_offer2);
-    contract.borrowing_offers_vecs.insert(&nft_collection_id, &new_vec);
+    let borrowing_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 20, token_id: Some("token_id_test1".to_string()), ..Default::default()};
+    let borrowing_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 10, token_id: Some("token_id_test2".to_string()), ..Default::default()};
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer1);
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer2);
     let best_offer = contract.get_best_borrowing_offer(nft_collection_id.clone()).unwrap();
     assert_eq!(best_offer.value, 10);
     assert_eq!(best_offer.offer_id, "offer_id_test2".to_string());
@@ -336,24 +536,14 @@ mod tests {
       .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("lending");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let lending_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 10, token_id: None};
-    let lending_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None};
-    new_vec.push(&lending_offer1);
-    new_vec.push(&lending_offer2);
-    contract.lending_offers_vecs.insert(&nft_collection_id, &new_vec);
-    let mut offer_map = LookupMap::new(b"lending_offer".to_vec());
-    offer_map.insert(&("offer_id_test1".to_string()), &lending_offer1);
-    offer_map.insert(&("offer_id_test2".to_string()), &lending_offer2);
-    contract.lending_offers.insert(&nft_collection_id.clone(), &offer_map);
+    let lending_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 10, token_id: None, ..Default::default()};
+    let lending_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None, ..Default::default()};
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer1);
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer2);
 
     contract.cancel_specific_lending_offer("offer_id_test1".to_string(), nft_collection_id.clone());
-    let lending_offer_vec = contract.lending_offers_vecs.get(&nft_collection_id).unwrap();
-    // ta certo isso? é pra ser 2 mesmo?
-    assert_eq!(lending_offer_vec.len(), 2);
-    assert_eq!(lending_offer_vec.get(0).unwrap().offer_id, "offer_id_test2".to_string());
+    assert_eq!(contract.get_lending_offers_book(&nft_collection_id).len(), 1);
+    assert_eq!(contract.get_best_lending_offer(nft_collection_id.clone()).unwrap().offer_id, "offer_id_test2".to_string());
   }
 
   #[test] 
@@ -369,24 +559,14 @@ mod tests {
       .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("borrowing");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let borrowing_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 20, token_id: Some("token_id1".to_string())};
-    let borrowing_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 10, token_id: Some("token_id2".to_string())};
-    new_vec.push(&borrowing_offer1);
-    new_vec.push(&borrowing_offer2);
-    contract.borrowing_offers_vecs.insert(&nft_collection_id, &new_vec);
-    let mut offer_map = LookupMap::new(b"borrowing_offer".to_vec());
-    offer_map.insert(&("offer_id_test1".to_string()), &borrowing_offer1);
-    offer_map.insert(&("offer_id_test2".to_string()), &borrowing_offer2);
-    contract.borrowing_offers.insert(&nft_collection_id.clone(), &offer_map);
+    let borrowing_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 20, token_id: Some("token_id1".to_string()), ..Default::default()};
+    let borrowing_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 10, token_id: Some("token_id2".to_string()), ..Default::default()};
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer1);
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer2);
 
     contract.cancel_specific_borrowing_offer("offer_id_test1".to_string(), nft_collection_id.clone());
-    let borrowing_offer_vec = contract.borrowing_offers_vecs.get(&nft_collection_id).unwrap();
-    // ta certo isso? é pra ser 2 mesmo?
-    assert_eq!(borrowing_offer_vec.len(), 2);
-    assert_eq!(borrowing_offer_vec.get(0).unwrap().offer_id, "offer_id_test2".to_string());
+    assert_eq!(contract.get_borrowing_offers_book(&nft_collection_id).len(), 1);
+    assert_eq!(contract.get_best_borrowing_offer(nft_collection_id.clone()).unwrap().offer_id, "offer_id_test2".to_string());
   }
 
   #[test]
@@ -402,25 +582,48 @@ mod tests {
       .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("lending");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let lending_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 10, token_id: None};
-    let lending_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None};
-    new_vec.push(&lending_offer1);
-    new_vec.push(&lending_offer2);
-    contract.lending_offers_vecs.insert(&nft_collection_id, &new_vec);
-    let mut offer_map = LookupMap::new(b"lending_offer".to_vec());
-    offer_map.insert(&("offer_id_test1".to_string()), &lending_offer1);
-    offer_map.insert(&("offer_id_test2".to_string()), &lending_offer2);
-    contract.lending_offers.insert(&nft_collection_id.clone(), &offer_map);
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{max_loan_value: 100, enabled: true, ..Default::default()});
+    let lending_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 10, token_id: None, ..Default::default()};
+    let lending_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None, ..Default::default()};
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer1);
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer2);
 
     let success = contract.choose_specific_lending_offer(nft_collection_id.clone(), "offer_id_test1".to_string(), "token_id1".to_string());
-    assert_eq!(success, true);
-    assert_eq!(contract.lending_offers_vecs.get(&nft_collection_id).unwrap().get(0).unwrap().offer_id, "offer_id_test2".to_string());
+    assert!(success);
+    assert_eq!(contract.get_lending_offers_book(&nft_collection_id).len(), 1);
+    assert_eq!(contract.get_best_lending_offer(nft_collection_id.clone()).unwrap().offer_id, "offer_id_test2".to_string());
 
   }
 
+  #[test]
+  #[should_panic(expected = "This NFT already backs another offer or loan")]
+  fn test_choose_specific_lending_offer_committed_collateral() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{max_loan_value: 100, enabled: true, ..Default::default()});
+    contract.insert_lending_offer(nft_collection_id.clone(), Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 10, ..Default::default()});
+    // the NFT is already escrowed by someone else's borrowing offer
+    contract.commit_collateral(&[Collateral{nft_collection_id: nft_collection_id.clone(), token_id: "token_id1".to_string()}]);
+    contract.choose_specific_lending_offer(nft_collection_id, "offer_id_test1".to_string(), "token_id1".to_string());
+  }
+
+  #[test]
+  #[should_panic(expected = "This offer doesn't lend against this NFT")]
+  fn test_choose_specific_lending_offer_outside_target() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{max_loan_value: 100, enabled: true, ..Default::default()});
+    let target = OfferTarget::TokenIds(vec!["token_id1".to_string()]);
+    contract.insert_lending_offer(nft_collection_id.clone(), Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 10, target: Some(target), ..Default::default()});
+    contract.choose_specific_lending_offer(nft_collection_id, "offer_id_test1".to_string(), "token_id2".to_string());
+  }
+
   #[test]
   fn test_choose_specific_borrowing_offer() {
     let mut context = get_context(accounts(1));
@@ -434,22 +637,16 @@ mod tests {
       .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let mut vector_id = nft_collection_id.clone();
-    vector_id.push_str("borrowing");
-    let mut new_vec = Vector::new(vector_id.into_bytes().to_vec());
-    let borrowing_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 20, token_id: Some("token_id1".to_string())};
-    let borrowing_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 10, token_id: Some("token_id2".to_string())};
-    new_vec.push(&borrowing_offer1);
-    new_vec.push(&borrowing_offer2);
-    contract.borrowing_offers_vecs.insert(&nft_collection_id, &new_vec);
-    let mut offer_map = LookupMap::new(b"borrowing_offer".to_vec());
-    offer_map.insert(&("offer_id_test1".to_string()), &borrowing_offer1);
-    offer_map.insert(&("offer_id_test2".to_string()), &borrowing_offer2);
-    contract.borrowing_offers.insert(&nft_collection_id.clone(), &offer_map);
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{max_loan_value: 100, enabled: true, ..Default::default()});
+    let borrowing_offer1 = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(0).into(), value: 20, token_id: Some("token_id1".to_string()), ..Default::default()};
+    let borrowing_offer2 = Offer{offer_id: "offer_id_test2".to_string(), owner_id: accounts(1).into(), value: 10, token_id: Some("token_id2".to_string()), ..Default::default()};
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer1);
+    contract.insert_borrowing_offer(nft_collection_id.clone(), borrowing_offer2);
 
     let success = contract.choose_specific_borrowing_offer(nft_collection_id.clone(), "offer_id_test1".to_string());
-    assert_eq!(success, true);
-    assert_eq!(contract.borrowing_offers_vecs.get(&nft_collection_id).unwrap().get(0).unwrap().offer_id, "offer_id_test2".to_string());
+    assert!(success);
+    assert_eq!(contract.get_borrowing_offers_book(&nft_collection_id).len(), 1);
+    assert_eq!(contract.get_best_borrowing_offer(nft_collection_id.clone()).unwrap().offer_id, "offer_id_test2".to_string());
   }
 
   #[test]
@@ -465,11 +662,14 @@ mod tests {
       .build());
 
     let nft_collection_id = "nft_collection_test".to_string();
-    let success = contract.post_lending_offer(nft_collection_id.clone(), U128(10));
-    assert_eq!(success, true);
-    assert_eq!(contract.lending_offers_vecs.get(&nft_collection_id).unwrap().get(0).unwrap().value, 10);
-    let offer_id = contract.lending_offers_vecs.get(&nft_collection_id).unwrap().get(0).unwrap().offer_id;
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()});
+    let success = contract.post_lending_offer(nft_collection_id.clone(), U128(10), U128(500), U128(604800), None, None);
+    assert!(success);
+    assert_eq!(contract.get_best_lending_offer(nft_collection_id.clone()).unwrap().value, 10);
+    let offer_id = contract.get_best_lending_offer(nft_collection_id.clone()).unwrap().offer_id;
     assert_eq!(contract.lending_offers.get(&nft_collection_id).unwrap().get(&offer_id).unwrap().value, 10);
+    // the excess deposit is credited to the lender's balance
+    assert_eq!(contract.balances.get(&accounts(0).to_string()).unwrap(), MINT_STORAGE_COST - 10);
     }
 
     #[test]
@@ -485,10 +685,93 @@ mod tests {
         .build());
 
       let nft_collection_id = "nft_collection_test".to_string();
-      let success = contract.post_borrowing_offer(nft_collection_id.clone(), U128(10), "token_id".to_string(), accounts(0).into());
-      assert_eq!(success, true);
-      assert_eq!(contract.borrowing_offers_vecs.get(&nft_collection_id).unwrap().get(0).unwrap().value, 10);
-      let offer_id = contract.borrowing_offers_vecs.get(&nft_collection_id).unwrap().get(0).unwrap().offer_id;
+      contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()});
+      let success = contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(0).into(), value: 10, token_id: Some("token_id".to_string()), apr: 500, loan_duration: 604800, ..Default::default()});
+      assert!(success);
+      assert_eq!(contract.get_best_borrowing_offer(nft_collection_id.clone()).unwrap().value, 10);
+      let offer_id = contract.get_best_borrowing_offer(nft_collection_id.clone()).unwrap().offer_id;
       assert_eq!(contract.borrowing_offers.get(&nft_collection_id).unwrap().get(&offer_id).unwrap().value, 10);
     }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_post_lending_offer_fills_several_borrowing_offers() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()});
+    contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(2).into(), value: 10, token_id: Some("token_id1".to_string()), apr: 800, loan_duration: 604800, ..Default::default()});
+    contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(3).into(), value: 15, token_id: Some("token_id2".to_string()), apr: 900, loan_duration: 604800, ..Default::default()});
+    contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(3).into(), value: 40, token_id: Some("token_id3".to_string()), apr: 900, loan_duration: 604800, ..Default::default()});
+
+    testing_env!(context
+      .attached_deposit(30)
+      .predecessor_account_id(accounts(4))
+      .build());
+    let success = contract.post_lending_offer(nft_collection_id.clone(), U128(30), U128(500), U128(604800), None, None);
+    // the two cheapest requests are funded and the 5 left rests in the book
+    assert!(success);
+    assert_eq!(contract.get_borrowing_offers_book(&nft_collection_id).len(), 1);
+    assert_eq!(contract.get_best_borrowing_offer(nft_collection_id.clone()).unwrap().value, 40);
+    let resting_offer = contract.get_best_lending_offer(nft_collection_id.clone()).unwrap();
+    assert_eq!(resting_offer.value, 5);
+    assert_eq!(resting_offer.owner_id, accounts(4).to_string());
+  }
+
+  #[test]
+  fn test_post_lending_offer_fills_within_gas() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()});
+    contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(2).into(), value: 10, token_id: Some("token_id1".to_string()), apr: 800, loan_duration: 604800, ..Default::default()});
+    contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(3).into(), value: 15, token_id: Some("token_id2".to_string()), apr: 900, loan_duration: 604800, ..Default::default()});
+
+    // only one loan fits in the gas attached, the rest of the offer rests in the book
+    testing_env!(context
+      .attached_deposit(30)
+      .prepaid_gas(150_000_000_000_000)
+      .predecessor_account_id(accounts(4))
+      .build());
+    assert!(contract.post_lending_offer(nft_collection_id.clone(), U128(30), U128(500), U128(604800), None, None));
+    assert_eq!(contract.get_best_borrowing_offer(nft_collection_id.clone()).unwrap().value, 15);
+    assert_eq!(contract.get_best_lending_offer(nft_collection_id).unwrap().value, 20);
+  }
+
+  #[test]
+  fn test_lending_pool_funds_several_borrowing_offers() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()});
+    testing_env!(context
+      .attached_deposit(50)
+      .predecessor_account_id(accounts(4))
+      .build());
+    assert!(contract.post_lending_offer(nft_collection_id.clone(), U128(50), U128(500), U128(604800), Some(U128(20)), None));
+
+    testing_env!(context
+      .attached_deposit(0)
+      .predecessor_account_id(accounts(1))
+      .build());
+    // each borrower draws from the pool, the last request doesn't fit in what's left
+    assert!(!contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(2).into(), value: 20, token_id: Some("token_id1".to_string()), apr: 800, loan_duration: 604800, ..Default::default()}));
+    assert!(!contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(3).into(), value: 20, token_id: Some("token_id2".to_string()), apr: 900, loan_duration: 604800, ..Default::default()}));
+    assert!(contract.place_borrowing_offer(nft_collection_id.clone(), Offer{owner_id: accounts(3).into(), value: 15, token_id: Some("token_id3".to_string()), apr: 900, loan_duration: 604800, ..Default::default()}));
+    let pool = contract.get_best_lending_offer(nft_collection_id.clone()).unwrap();
+    assert_eq!(pool.value, 10);
+    assert_eq!(pool.max_value_per_loan, Some(20));
+
+    // cancelling releases only the capacity that wasn't lent
+    testing_env!(context
+      .predecessor_account_id(accounts(4))
+      .build());
+    contract.cancel_specific_lending_offer(pool.offer_id, nft_collection_id.clone());
+    assert!(contract.get_best_lending_offer(nft_collection_id).is_none());
+  }
+}
@@ -1,19 +1,341 @@
 use crate::*;
 use serde_json::Value;
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum LoanStatus {
+  // note and receipt are being minted
+  Pending,
+  Active,
+  Repaid,
+  // the note holder took the collateral
+  Defaulted,
+  // the collateral is being auctioned
+  Liquidating,
+  // the note or receipt failed to mint and the loan was rolled back
+  Cancelled
+}
+
+impl LoanStatus {
+
+  pub fn can_transition_to(&self, next_status: &LoanStatus) -> bool {
+    matches!(
+      (self, next_status),
+      (LoanStatus::Pending, LoanStatus::Active)
+        | (LoanStatus::Pending, LoanStatus::Cancelled)
+        | (LoanStatus::Active, LoanStatus::Repaid)
+        | (LoanStatus::Active, LoanStatus::Defaulted)
+        | (LoanStatus::Active, LoanStatus::Liquidating)
+        | (LoanStatus::Liquidating, LoanStatus::Defaulted)
+    )
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LoanView {
+  pub token_id: TokenId,
+  pub value: U128,
+  pub apr: U128,
+  pub start_time: U128,
+  pub expiration_time: U128,
+  pub amount_repaid: U128,
+  pub warranty_collection: AccountId,
+  pub warranty_token_id: String,
+  pub bundled_collateral: Vec<Collateral>,
+  pub currency: Option<AccountId>,
+  pub status: LoanStatus,
+  pub accrued_interest: U128,
+  pub outstanding_balance: U128,
+  // seconds until expiration, 0 once expired
+  pub time_remaining: U128
+}
+
 #[near_bindgen]
 impl LendingNftCollateral {
 
-  pub fn post_loan(&mut self, lender_account_id: AccountId, borrower_account_id: AccountId, warranty_collection: AccountId, warranty_token_id: TokenId, loan_value: U128) -> bool {
-    let loan = Loan {
-      value: loan_value.0,
-      expiration_time: env::block_timestamp() as u128 + self.loan_expiration_seconds_limit,
-      warranty_collection: warranty_collection.clone(),
-      warranty_token_id: warranty_token_id.clone(),
+  // continues post_loan once the warranty collection reports who owns the collateral
+  #[private]
+  pub fn resolve_collateral_custody(&mut self, nft_collection_id: NftCollection, lending_offer: Offer, borrowing_offer: Offer, restore_lending_offer: bool) -> bool {
+    // every piece of a bundle has to be held by the contract, and committed to this offer alone
+    let collateral = borrowing_offer.collateral(&nft_collection_id);
+    let held: Vec<bool> = (0..env::promise_results_count())
+      .map(|index| self.get_token_owner_from_promise(index) == Some(env::current_account_id()))
+      .collect();
+    let committed = collateral.iter()
+      .all(|piece| self.committed_collateral.contains(&(piece.nft_collection_id.clone(), piece.token_id.clone())));
+    let custody_verified = committed && held.iter().all(|held| *held);
+
+    if !custody_verified {
+      // pieces the contract doesn't hold can't stay committed, the ones it holds for this offer
+      // go back to the borrower since the offer already left the book
+      let mut not_held = Vec::new();
+      let mut returned = Vec::new();
+      for (piece, held) in collateral.into_iter().zip(held) {
+        if !held {
+          not_held.push(piece);
+        } else if self.committed_collateral.contains(&(piece.nft_collection_id.clone(), piece.token_id.clone())) {
+          returned.push(piece);
+        }
+      }
+      self.release_collateral(&not_held);
+      if !returned.is_empty() {
+        self.transfer_collateral(borrowing_offer.owner_id.clone(), returned);
+      }
+      // the lending offer goes back to the book with its escrow, otherwise the lender gets refunded
+      if restore_lending_offer {
+        self.restore_lending_offer(nft_collection_id, lending_offer);
+      } else {
+        self.pay_out(lending_offer.owner_id, &lending_offer.currency, lending_offer.value);
+      }
+      return false;
+    }
+
+    // the lender escrowed the full offer, return what wasn't borrowed
+    if lending_offer.value > borrowing_offer.value {
+      self.pay_out(lending_offer.owner_id.clone(), &lending_offer.currency, lending_offer.value - borrowing_offer.value);
+    }
+    self.originate_loan(nft_collection_id, lending_offer, borrowing_offer);
+    true
+  }
+
+  // pays the borrower once both tokens exist, otherwise rolls the loan back
+  #[private]
+  pub fn resolve_loan_origination(&mut self, token_id: TokenId, lender_account_id: AccountId, borrower_account_id: AccountId) -> bool {
+    let note_minted = matches!(env::promise_result(0), PromiseResult::Successful(_));
+    let receipt_minted = matches!(env::promise_result(1), PromiseResult::Successful(_));
+    let mut loan = self.loans.get(&token_id).unwrap();
+
+    if note_minted && receipt_minted {
+      self.set_loan_status(&token_id, &mut loan, LoanStatus::Active);
+      // escrowed lender funds go to the borrower, minus the origination fee
+      let fee = self.charge_origination_fee(&loan.currency, loan.value);
+      self.pay_out(borrower_account_id, &loan.currency, loan.value - fee);
+      return true;
+    }
+
+    self.set_loan_status(&token_id, &mut loan, LoanStatus::Cancelled);
+    if note_minted {
+      ext_nft_contract::nft_burn(token_id.clone(), &self.note_address, NO_DEPOSIT, BASE_GAS);
+    }
+    if receipt_minted {
+      ext_nft_contract::nft_burn(token_id.clone(), &self.receipt_address, NO_DEPOSIT, BASE_GAS);
+    }
+    self.transfer_collateral(borrower_account_id.clone(), loan.collateral());
+    self.pay_out(lender_account_id.clone(), &loan.currency, loan.value);
+
+    LoanOriginationFailed {
+      token_id: &token_id,
+      lender_id: &lender_account_id,
+      borrower_id: &borrower_account_id,
+      warranty_collection: &loan.warranty_collection,
+      warranty_token_id: &loan.warranty_token_id,
+      loan_value: U128(loan.value),
+      note_minted,
+      receipt_minted
+    }.emit();
+    false
+  }
+
+  // called by the receipt holder, payments can be partial and are forwarded to the note owner
+  #[payable]
+  pub fn pay_loan(&mut self, token_id: TokenId, payment_value: U128) -> Promise {
+    let loan = self.loans.get(&token_id).expect("Loan not found");
+    assert!(loan.currency.is_none(), "This loan must be repaid through ft_transfer_call");
+    let payer_id = env::predecessor_account_id();
+    self.lock_funds(payer_id.clone(), payment_value.0);
+    self.request_loan_payment(token_id, &loan, payer_id, payment_value)
+  }
+
+  // only the receipt holder can pay, anything else goes back to the payer's balance
+  #[private]
+  pub fn resolve_loan_payment(&mut self, token_id: TokenId, payer_id: AccountId, payment_value: U128) -> bool {
+    let receipt_owner_id = self.get_token_owner_from_promise(0);
+    let note_owner_id = self.get_token_owner_from_promise(1);
+    let mut loan = self.loans.get(&token_id).unwrap();
+
+    // the loan may have been settled while the owners were being checked
+    if receipt_owner_id != Some(payer_id.clone()) || note_owner_id.is_none() || loan.status != LoanStatus::Active {
+      self.credit_funds(payer_id, &loan.currency, payment_value.0);
+      return false;
+    }
+
+    let outstanding_balance = self.calculate_outstanding_balance(&loan);
+    let paid_value = std::cmp::min(payment_value.0, outstanding_balance);
+    if payment_value.0 > paid_value {
+      self.credit_funds(payer_id.clone(), &loan.currency, payment_value.0 - paid_value);
+    }
+    // principal is repaid first, the protocol takes its share of whatever goes beyond it
+    let interest_paid = (loan.amount_repaid + paid_value).saturating_sub(loan.value) - loan.amount_repaid.saturating_sub(loan.value);
+    let fee = self.charge_interest_fee(&loan.currency, interest_paid);
+    loan.amount_repaid += paid_value;
+    if outstanding_balance > paid_value {
+      self.loans.insert(&token_id, &loan);
+    } else {
+      self.set_loan_status(&token_id, &mut loan, LoanStatus::Repaid);
+    }
+    if paid_value > fee {
+      self.pay_out(note_owner_id.unwrap(), &loan.currency, paid_value - fee);
+    }
+
+    // collateral is only released once nothing is left to pay
+    if outstanding_balance > paid_value {
+      return true;
+    }
+    self.transfer_collateral(payer_id, loan.collateral());
+    self.burn_loan_tokens(token_id);
+    true
+  }
+
+  //function to call loan
+  #[payable]
+  pub fn transfer_warranty_loan(&mut self, token_id: TokenId, sender_owner_id: AccountId) -> Promise {
+    assert!(env::predecessor_account_id() == self.note_address, "Only note contract can call this function");
+    let mut loan = self.loans.get(&token_id).expect("Loan not found");
+    self.assert_active_loan(&loan);
+    assert!(self.get_grace_period_end(&loan) < env::block_timestamp() as u128, "This loan's grace period hasn't ended yet");
+    self.set_loan_status(&token_id, &mut loan, LoanStatus::Defaulted);
+    self.transfer_collateral(sender_owner_id, loan.collateral());
+    ext_nft_contract::nft_burn(
+      token_id.clone(), 
+      &self.note_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    );
+
+    ext_nft_contract::nft_burn(
+      token_id.clone(), 
+      &self.receipt_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    )
+  }
+
+  pub fn get_loan(&self, token_id: TokenId) -> Option<LoanView> {
+    let loan = self.loans.get(&token_id)?;
+    let current_time = env::block_timestamp() as u128;
+    // only active loans keep accruing, the others have nothing left to pay
+    let (accrued_interest, outstanding_balance) = if loan.status == LoanStatus::Active {
+      (self.calculate_accrued_interest(&loan), self.calculate_outstanding_balance(&loan))
+    } else {
+      (0, 0)
     };
+    Some(LoanView {
+      token_id,
+      value: U128(loan.value),
+      apr: U128(loan.apr),
+      start_time: U128(loan.start_time),
+      expiration_time: U128(loan.expiration_time),
+      amount_repaid: U128(loan.amount_repaid),
+      accrued_interest: U128(accrued_interest),
+      outstanding_balance: U128(outstanding_balance),
+      time_remaining: U128(loan.expiration_time.saturating_sub(current_time) / NANOSECONDS_PER_SECOND),
+      warranty_collection: loan.warranty_collection,
+      warranty_token_id: loan.warranty_token_id,
+      bundled_collateral: loan.bundled_collateral,
+      currency: loan.currency,
+      status: loan.status
+    })
+  }
+}
 
-    self.loans.insert(&self.token_id_counter.to_string(), &loan);
+impl LendingNftCollateral {
+
+  // lender, apr and duration come from the lending offer, borrower and collateral from the borrowing offer
+  pub fn post_loan(&mut self, nft_collection_id: NftCollection, lending_offer: Offer, borrowing_offer: Offer, restore_lending_offer: bool) -> Promise {
+    assert!(borrowing_offer.value <= self.get_ltv_loan_limit(&nft_collection_id, &borrowing_offer), "The loan value is above the collection's maximum loan-to-value");
+    let custody_check = borrowing_offer.bundled_collateral.iter().fold(
+      ext_nft_contract::nft_token(
+        borrowing_offer.token_id.clone().unwrap(),
+        &nft_collection_id,
+        NO_DEPOSIT,
+        BASE_GAS
+      ),
+      |promise, collateral| promise.and(ext_nft_contract::nft_token(
+        collateral.token_id.clone(),
+        &collateral.nft_collection_id,
+        NO_DEPOSIT,
+        BASE_GAS
+      ))
+    );
+    custody_check.then(ext_self::resolve_collateral_custody(
+      nft_collection_id.clone(),
+      lending_offer,
+      borrowing_offer,
+      restore_lending_offer,
+      &env::current_account_id(),
+      NO_DEPOSIT,
+      CUSTODY_CALLBACK_GAS
+    ))
+  }
+
+  // checks who holds the receipt and the note before the payment is applied, the payment value
+  // is already held by the contract
+  pub fn request_loan_payment(&mut self, token_id: TokenId, loan: &Loan, payer_id: AccountId, payment_value: U128) -> Promise {
+    self.assert_active_loan(loan);
+    assert!(self.calculate_outstanding_balance(loan) > 0, "This loan has already been paid");
+    assert!(self.get_grace_period_end(loan) >= env::block_timestamp() as u128, "The grace period for this loan is over");
 
+    ext_nft_contract::nft_token(
+      token_id.clone(),
+      &self.receipt_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    ).and(ext_nft_contract::nft_token(
+      token_id.clone(),
+      &self.note_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    )).then(ext_self::resolve_loan_payment(
+      token_id,
+      payer_id,
+      payment_value,
+      &env::current_account_id(),
+      NO_DEPOSIT,
+      CALLBACK_GAS
+    ))
+  }
+
+  // the borrowing offer's value is what gets lent
+  pub fn originate_loan(&mut self, nft_collection_id: NftCollection, lending_offer: Offer, borrowing_offer: Offer) -> Promise {
+    let lender_account_id = lending_offer.owner_id.clone();
+    let borrower_account_id = borrowing_offer.owner_id;
+    let loan = self.build_loan(&lending_offer, nft_collection_id, borrowing_offer.token_id.unwrap(), borrowing_offer.bundled_collateral, borrowing_offer.value);
+
+    let token_id = self.token_id_counter.to_string();
+    self.token_id_counter += 1;
+    self.loans.insert(&token_id, &loan);
+    self.index_loan_owners(&token_id, &lender_account_id, &borrower_account_id);
+
+    // mint note and receipt, the loan is only settled once both exist
+    self.mint_loan_tokens(token_id.clone(), &loan, lender_account_id.clone(), borrower_account_id.clone())
+      .then(ext_self::resolve_loan_origination(
+        token_id,
+        lender_account_id,
+        borrower_account_id,
+        &env::current_account_id(),
+        NO_DEPOSIT,
+        CALLBACK_GAS
+      ))
+  }
+
+  // apr and duration come from the lending offer, the loan starts at the current block
+  pub fn build_loan(&self, lending_offer: &Offer, warranty_collection: NftCollection, warranty_token_id: TokenId, bundled_collateral: Vec<Collateral>, loan_value: u128) -> Loan {
+    Loan {
+      value: loan_value,
+      apr: lending_offer.apr,
+      start_time: env::block_timestamp() as u128,
+      expiration_time: env::block_timestamp() as u128 + lending_offer.loan_duration * NANOSECONDS_PER_SECOND,
+      amount_repaid: 0,
+      warranty_collection,
+      warranty_token_id,
+      bundled_collateral,
+      currency: lending_offer.currency.clone(),
+      status: LoanStatus::Pending
+    }
+  }
+
+  pub fn mint_loan_tokens(&self, token_id: TokenId, loan: &Loan, lender_account_id: AccountId, borrower_account_id: AccountId) -> Promise {
     let token_metadata = TokenMetadata {
       title: Some("Loan".to_string()),
       // change this later
@@ -28,95 +350,511 @@ impl LendingNftCollateral {
       extra: None,
       reference: None,
       reference_hash: None,
-      loan_value: Some(loan_value.0),
-      loan_expiration_time: None,
-      warranty_collection: Some(warranty_collection.clone()),
-      warranty_token_id: Some(warranty_token_id.clone())
+      loan_value: Some(loan.value),
+      loan_expiration_time: Some(loan.expiration_time),
+      warranty_collection: Some(loan.warranty_collection.clone()),
+      warranty_token_id: Some(loan.warranty_token_id.clone())
     };
 
-    // mint note
+    // mint note to the lender and receipt to the borrower
     ext_nft_contract::nft_mint(
-      self.token_id_counter.to_string(),
+      token_id.clone(),
       lender_account_id,
       token_metadata.clone(),
       &self.note_address,
       NO_DEPOSIT,
       BASE_GAS
-    );
-    // mint receipt
-    ext_nft_contract::nft_mint(
-      self.token_id_counter.to_string(),
+    ).and(ext_nft_contract::nft_mint(
+      token_id,
       borrower_account_id,
-      token_metadata.clone(),
+      token_metadata,
       &self.receipt_address,
       NO_DEPOSIT,
       BASE_GAS
-    );
-    self.token_id_counter = self.token_id_counter + 1;
-    true
+    ))
   }
 
-  #[payable]
-  pub fn pay_loan(&mut self, token_id: TokenId, note_owner_id: AccountId) -> Promise {
-    // only receipt contract can call this function
-    assert!(env::predecessor_account_id() == self.receipt_address, "Only receipt contract can call this function");
-    let loan = self.loans.get(&token_id).unwrap();
-    
-    let borrower_balance = self.balances.get(&env::predecessor_account_id()).unwrap_or(0);
-    assert!(borrower_balance >= loan.value, "You don't have enough credit for this transaction");
-    self.balances.insert(&env::predecessor_account_id(), &(borrower_balance - loan.value));
-    Promise::new(note_owner_id.clone()).transfer(loan.value);
-    ext_nft_contract::nft_transfer(
-      env::current_account_id(), 
-      loan.warranty_token_id,
-      None,
-      None,
-      &loan.warranty_collection,
-      NO_DEPOSIT,
-      BASE_GAS
-    );
+  pub fn burn_loan_tokens(&self, token_id: TokenId) -> Promise {
     ext_nft_contract::nft_burn(
-      token_id.clone(), 
+      token_id.clone(),
       &self.note_address,
       NO_DEPOSIT,
       BASE_GAS
-    );
-
-    ext_nft_contract::nft_burn(
-      token_id.clone(), 
+    ).and(ext_nft_contract::nft_burn(
+      token_id,
       &self.receipt_address,
       NO_DEPOSIT,
       BASE_GAS
-    )
+    ))
   }
 
-  //function to call loan
-  #[payable]
-  pub fn transfer_warranty_loan(&mut self, token_id: TokenId, sender_owner_id: AccountId) -> Promise {
-    assert!(env::predecessor_account_id() == self.note_address, "Only note contract can call this function");
-    let loan = self.loans.get(&token_id).unwrap();
-    assert!(loan.expiration_time < env::block_timestamp() as u128, "This loan hasn't expired yet");
-    ext_nft_contract::nft_transfer(
-      env::current_account_id(), 
-      loan.warranty_token_id,
-      None,
-      None,
-      &sender_owner_id.clone(),      
-      NO_DEPOSIT,
-      BASE_GAS
+  // simple interest accrued pro-rata from the loan start up to the current block
+  pub fn calculate_accrued_interest(&self, loan: &Loan) -> u128 {
+    let elapsed_seconds = (env::block_timestamp() as u128).saturating_sub(loan.start_time) / NANOSECONDS_PER_SECOND;
+    let yearly_interest = loan.value * loan.apr / BASIS_POINTS;
+    yearly_interest * elapsed_seconds / YEAR_IN_SECONDS
+  }
+
+  // a one-off fee over the loan value once it's past expiration
+  pub fn calculate_late_fee(&self, loan: &Loan) -> u128 {
+    if env::block_timestamp() as u128 <= loan.expiration_time {
+      return 0;
+    }
+    let late_fee = self.collection_params.get(&loan.warranty_collection).map_or(0, |params| params.late_fee);
+    loan.value * late_fee / BASIS_POINTS
+  }
+
+  // interest accrues over the whole principal, partial payments only reduce what is owed
+  pub fn calculate_outstanding_balance(&self, loan: &Loan) -> u128 {
+    (loan.value + self.calculate_accrued_interest(loan) + self.calculate_late_fee(loan)).saturating_sub(loan.amount_repaid)
+  }
+
+  // last moment the borrower can repay, after it the note holder can claim the collateral
+  pub fn get_grace_period_end(&self, loan: &Loan) -> u128 {
+    let grace_period = self.collection_params.get(&loan.warranty_collection).map_or(0, |params| params.grace_period);
+    loan.expiration_time + grace_period * NANOSECONDS_PER_SECOND
+  }
+
+  pub fn get_token_owner_from_promise(&self, result_index: u64) -> Option<AccountId> {
+    match env::promise_result(result_index) {
+      PromiseResult::Successful(value) => match serde_json::from_slice::<Option<Token>>(&value) {
+        Ok(Some(token)) => Some(token.owner_id),
+        _ => None
+      },
+      _ => None
+    }
+  }
+
+  // stores the loan with its new status, panics on transitions the lifecycle doesn't allow
+  pub fn set_loan_status(&mut self, token_id: &TokenId, loan: &mut Loan, status: LoanStatus) {
+    assert!(loan.status.can_transition_to(&status), "Invalid loan status transition");
+    loan.status = status;
+    self.loans.insert(token_id, loan);
+    self.update_loan_indexes(token_id, loan);
+  }
+
+  pub fn assert_active_loan(&self, loan: &Loan) {
+    assert!(loan.status == LoanStatus::Active, "This loan is not active");
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+pub mod tests {
+  use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::{MockedBlockchain, RuntimeFeesConfig, VMConfig};
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  // an active loan on the test collection, other modules override the fields they need
+  pub fn sample_loan(value: u128, apr: u128) -> Loan {
+    Loan {
+      value,
+      apr,
+      start_time: 0,
+      expiration_time: 0,
+      amount_repaid: 0,
+      warranty_collection: "nft_collection_test".to_string(),
+      warranty_token_id: "token_id".to_string(),
+      bundled_collateral: Vec::new(),
+      currency: None,
+      status: LoanStatus::Active
+    }
+  }
+
+  #[test]
+  fn test_calculate_accrued_interest() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // 10% apr over half a year
+    testing_env!(context
+      .block_timestamp((YEAR_IN_SECONDS / 2 * NANOSECONDS_PER_SECOND) as u64)
+      .build());
+    let interest = contract.calculate_accrued_interest(&sample_loan(1000, 1000));
+    assert_eq!(interest, 50);
+  }
+
+  fn token_result(owner_id: AccountId) -> PromiseResult {
+    let token = Token{token_id: "token_id".to_string(), owner_id, metadata: None, approved_account_ids: None, royalty: None};
+    PromiseResult::Successful(serde_json::to_vec(&Some(token)).unwrap())
+  }
+
+  fn collateral_token_result(owner_id: AccountId) -> Vec<PromiseResult> {
+    vec![token_result(owner_id)]
+  }
+
+  #[test]
+  fn test_resolve_collateral_custody() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      collateral_token_result(accounts(0).into())
     );
-    ext_nft_contract::nft_burn(
-      token_id.clone(), 
-      &self.note_address,
-      NO_DEPOSIT,
-      BASE_GAS
+    let nft_collection_id = "nft_collection_test".to_string();
+    let lending_offer = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None, apr: 1000, loan_duration: 604800, ..Default::default()};
+    let borrowing_offer = Offer{owner_id: accounts(2).into(), value: 20, token_id: Some("token_id".to_string()), ..Default::default()};
+    contract.commit_collateral(&borrowing_offer.collateral(&nft_collection_id));
+    let success = contract.resolve_collateral_custody(nft_collection_id.clone(), lending_offer, borrowing_offer, true);
+    assert!(success);
+    assert_eq!(contract.token_id_counter, 1);
+    let loan = contract.loans.get(&"0".to_string()).unwrap();
+    assert_eq!(loan.value, 20);
+    assert_eq!(loan.apr, 1000);
+    assert_eq!(loan.warranty_token_id, "token_id".to_string());
+  }
+
+  #[test]
+  fn test_resolve_collateral_custody_not_owner() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // the collateral still belongs to the borrower
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      collateral_token_result(accounts(2).into())
+    );
+    let nft_collection_id = "nft_collection_test".to_string();
+    let lending_offer = Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(1).into(), value: 20, token_id: None, apr: 1000, loan_duration: 604800, ..Default::default()};
+    let borrowing_offer = Offer{owner_id: accounts(2).into(), value: 20, token_id: Some("token_id".to_string()), ..Default::default()};
+    contract.commit_collateral(&borrowing_offer.collateral(&nft_collection_id));
+    let success = contract.resolve_collateral_custody(nft_collection_id.clone(), lending_offer, borrowing_offer, true);
+    assert!(!success);
+    assert_eq!(contract.token_id_counter, 0);
+    // the claim on an NFT the contract doesn't hold is dropped
+    assert!(!contract.committed_collateral.contains(&(nft_collection_id.clone(), "token_id".to_string())));
+    assert!(contract.loans.get(&"0".to_string()).is_none());
+    // the lending offer is back in the book
+    assert_eq!(contract.get_best_lending_offer(nft_collection_id.clone()).unwrap().offer_id, "offer_id_test1".to_string());
+    assert_eq!(contract.lending_offers.get(&nft_collection_id).unwrap().get(&"offer_id_test1".to_string()).unwrap().value, 20);
+  }
+
+  #[test]
+  fn test_resolve_collateral_custody_missing_bundle_piece() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // the contract holds the offer's NFT but the rest of the bundle left
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![token_result(accounts(0).into()), token_result(accounts(2).into())]
     );
+    let nft_collection_id = "nft_collection_test".to_string();
+    let bundle_collection_id = "bundle_collection_test".to_string();
+    let lending_offer = Offer{owner_id: accounts(1).into(), value: 20, apr: 1000, loan_duration: 604800, ..Default::default()};
+    let bundled_collateral = vec![Collateral{nft_collection_id: bundle_collection_id.clone(), token_id: "bundled_token_id".to_string()}];
+    let borrowing_offer = Offer{owner_id: accounts(2).into(), value: 20, token_id: Some("token_id".to_string()), bundled_collateral, ..Default::default()};
+    contract.commit_collateral(&borrowing_offer.collateral(&nft_collection_id));
+    let success = contract.resolve_collateral_custody(nft_collection_id.clone(), lending_offer, borrowing_offer, false);
+    assert!(!success);
+    assert!(!contract.committed_collateral.contains(&(nft_collection_id.clone(), "token_id".to_string())));
+    assert!(!contract.committed_collateral.contains(&(bundle_collection_id.clone(), "bundled_token_id".to_string())));
+    // the NFT the contract holds goes back to the borrower
+    let receivers: Vec<String> = near_sdk::test_utils::get_created_receipts()
+      .iter()
+      .map(|receipt| serde_json::from_str::<Value>(&serde_json::to_string(receipt).unwrap()).unwrap())
+      .filter_map(|receipt| receipt["receiver_id"].as_str().map(|receiver_id| receiver_id.to_string()))
+      .collect();
+    assert!(receivers.contains(&nft_collection_id));
+    assert!(!receivers.contains(&bundle_collection_id));
+  }
 
-    ext_nft_contract::nft_burn(
-      token_id.clone(), 
-      &self.receipt_address,
-      NO_DEPOSIT,
-      BASE_GAS
-    )
+  #[test]
+  fn test_resolve_loan_origination() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Successful(vec![]), PromiseResult::Successful(vec![])]
+    );
+    let mut loan = sample_loan(1000, 1000);
+    loan.status = LoanStatus::Pending;
+    contract.loans.insert(&"0".to_string(), &loan);
+    let success = contract.resolve_loan_origination("0".to_string(), accounts(1).into(), accounts(2).into());
+    assert!(success);
+    assert_eq!(contract.loans.get(&"0".to_string()).unwrap().status, LoanStatus::Active);
+  }
+
+  #[test]
+  fn test_resolve_loan_origination_failed_mint() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Successful(vec![]), PromiseResult::Failed]
+    );
+    let mut loan = sample_loan(1000, 1000);
+    loan.status = LoanStatus::Pending;
+    contract.loans.insert(&"0".to_string(), &loan);
+    let success = contract.resolve_loan_origination("0".to_string(), accounts(1).into(), accounts(2).into());
+    assert!(!success);
+    assert_eq!(contract.loans.get(&"0".to_string()).unwrap().status, LoanStatus::Cancelled);
+    let logs = get_logs();
+    assert_eq!(logs.len(), 1);
+    assert!(logs[0].starts_with("EVENT_JSON:"));
+    assert!(logs[0].contains("\"event\":\"loan_origination_failed\""));
+    assert!(logs[0].contains("\"receipt_minted\":false"));
+  }
+
+  #[test]
+  fn test_calculate_outstanding_balance() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // 20% apr over a full year with part of it already repaid
+    testing_env!(context
+      .block_timestamp((YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND) as u64)
+      .build());
+    let mut loan = sample_loan(1000, 2000);
+    loan.amount_repaid = 700;
+    assert_eq!(contract.calculate_outstanding_balance(&loan), 500);
+  }
+
+  #[test]
+  fn test_calculate_outstanding_balance_with_late_fee() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // 5% late fee on top of a year of 20% interest
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{grace_period: 172800, late_fee: 500, ..Default::default()});
+    testing_env!(context
+      .block_timestamp((YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND) as u64)
+      .build());
+    let loan = sample_loan(1000, 2000);
+    assert_eq!(contract.calculate_late_fee(&loan), 50);
+    assert_eq!(contract.calculate_outstanding_balance(&loan), 1250);
+    assert_eq!(contract.get_grace_period_end(&loan), 172800 * NANOSECONDS_PER_SECOND);
+  }
+
+  #[test]
+  fn test_pay_loan() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // payment is taken from the attached deposit and the balance
+    testing_env!(context
+      .attached_deposit(100)
+      .predecessor_account_id(accounts(2))
+      .build());
+    contract.loans.insert(&"0".to_string(), &sample_loan(1000, 2000));
+    contract.balances.insert(&accounts(2).into(), &(500));
+    contract.pay_loan("0".to_string(), U128(300));
+    assert_eq!(contract.balances.get(&accounts(2).into()).unwrap(), 300);
+  }
+
+  #[test]
+  #[should_panic(expected = "This loan is not active")]
+  fn test_pay_loan_repaid() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .attached_deposit(100)
+      .predecessor_account_id(accounts(2))
+      .build());
+    let mut loan = sample_loan(1000, 2000);
+    loan.status = LoanStatus::Repaid;
+    contract.loans.insert(&"0".to_string(), &loan);
+    contract.pay_loan("0".to_string(), U128(100));
+  }
+
+  #[test]
+  fn test_loan_status_transitions() {
+    assert!(LoanStatus::Pending.can_transition_to(&LoanStatus::Active));
+    assert!(LoanStatus::Active.can_transition_to(&LoanStatus::Liquidating));
+    assert!(LoanStatus::Liquidating.can_transition_to(&LoanStatus::Defaulted));
+    assert!(!LoanStatus::Repaid.can_transition_to(&LoanStatus::Active));
+    assert!(!LoanStatus::Pending.can_transition_to(&LoanStatus::Repaid));
+    assert!(!LoanStatus::Cancelled.can_transition_to(&LoanStatus::Active));
+  }
+
+  #[test]
+  fn test_get_loan() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .block_timestamp((YEAR_IN_SECONDS / 2 * NANOSECONDS_PER_SECOND) as u64)
+      .build());
+    let mut loan = sample_loan(1000, 2000);
+    loan.expiration_time = YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND;
+    contract.loans.insert(&"0".to_string(), &loan);
+    let loan_view = contract.get_loan("0".to_string()).unwrap();
+    assert_eq!(loan_view.status, LoanStatus::Active);
+    assert_eq!(loan_view.accrued_interest.0, 100);
+    assert_eq!(loan_view.outstanding_balance.0, 1100);
+    assert_eq!(loan_view.time_remaining.0, YEAR_IN_SECONDS / 2);
+    assert!(contract.get_loan("1".to_string()).is_none());
+
+    loan.status = LoanStatus::Repaid;
+    contract.loans.insert(&"0".to_string(), &loan);
+    let loan_view = contract.get_loan("0".to_string()).unwrap();
+    assert_eq!(loan_view.accrued_interest.0, 0);
+    assert_eq!(loan_view.outstanding_balance.0, 0);
+  }
+
+  #[test]
+  #[should_panic(expected = "The grace period for this loan is over")]
+  fn test_pay_loan_after_grace_period() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{grace_period: 172800, late_fee: 500, ..Default::default()});
+    testing_env!(context
+      .block_timestamp((172801 * NANOSECONDS_PER_SECOND) as u64)
+      .attached_deposit(300)
+      .predecessor_account_id(accounts(2))
+      .build());
+    contract.loans.insert(&"0".to_string(), &sample_loan(1000, 2000));
+    contract.pay_loan("0".to_string(), U128(300));
+  }
+
+  #[test]
+  #[should_panic(expected = "This loan's grace period hasn't ended yet")]
+  fn test_transfer_warranty_loan_during_grace_period() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{grace_period: 172800, late_fee: 500, ..Default::default()});
+    testing_env!(context
+      .block_timestamp((86400 * NANOSECONDS_PER_SECOND) as u64)
+      .predecessor_account_id(accounts(2))
+      .build());
+    contract.loans.insert(&"0".to_string(), &sample_loan(1000, 2000));
+    contract.transfer_warranty_loan("0".to_string(), accounts(4).into());
+  }
+
+  #[test]
+  fn test_resolve_loan_payment_partial() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![token_result(accounts(2).into()), token_result(accounts(1).into())]
+    );
+    contract.loans.insert(&"0".to_string(), &sample_loan(1000, 2000));
+    let success = contract.resolve_loan_payment("0".to_string(), accounts(2).into(), U128(400));
+    assert!(success);
+    let loan = contract.loans.get(&"0".to_string()).unwrap();
+    assert_eq!(loan.amount_repaid, 400);
+    assert_eq!(contract.calculate_outstanding_balance(&loan), 600);
+  }
+
+  #[test]
+  fn test_resolve_loan_payment_with_interest() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // 20% apr over a full year, the excess goes back to the payer's balance
+    testing_env!(
+      context
+        .block_timestamp((YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND) as u64)
+        .predecessor_account_id(accounts(0))
+        .build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![token_result(accounts(2).into()), token_result(accounts(1).into())]
+    );
+    contract.loans.insert(&"0".to_string(), &sample_loan(1000, 2000));
+    let success = contract.resolve_loan_payment("0".to_string(), accounts(2).into(), U128(1500));
+    assert!(success);
+    assert_eq!(contract.loans.get(&"0".to_string()).unwrap().amount_repaid, 1200);
+    assert_eq!(contract.balances.get(&accounts(2).into()).unwrap(), 300);
+  }
+
+  #[test]
+  fn test_resolve_loan_payment_interest_fee() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+    contract.set_protocol_fees(U128(0), U128(1000));
+
+    // 900 of principal was already repaid, 100 of this payment is principal and 200 is interest
+    testing_env!(
+      context
+        .block_timestamp((YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND) as u64)
+        .predecessor_account_id(accounts(0))
+        .build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![token_result(accounts(2).into()), token_result(accounts(1).into())]
+    );
+    let mut loan = sample_loan(1000, 2000);
+    loan.amount_repaid = 900;
+    contract.loans.insert(&"0".to_string(), &loan);
+    contract.resolve_loan_payment("0".to_string(), accounts(2).into(), U128(300));
+    assert_eq!(contract.get_treasury_balance(None).0, 20);
+  }
+
+  #[test]
+  fn test_resolve_loan_payment_not_receipt_owner() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![token_result(accounts(2).into()), token_result(accounts(1).into())]
+    );
+    contract.loans.insert(&"0".to_string(), &sample_loan(1000, 2000));
+    let success = contract.resolve_loan_payment("0".to_string(), accounts(4).into(), U128(400));
+    assert!(!success);
+    assert_eq!(contract.loans.get(&"0".to_string()).unwrap().amount_repaid, 0);
+    assert_eq!(contract.balances.get(&accounts(4).into()).unwrap(), 400);
   }
-}
\ No newline at end of file
+}
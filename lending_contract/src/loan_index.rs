@@ -0,0 +1,205 @@
+use crate::*;
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // called by the note and receipt contracts after one of their tokens changes hands, so the
+  // lender and borrower indexes follow the secondary market
+  pub fn update_loan_token_owner(&mut self, token_id: TokenId, new_owner_id: AccountId) {
+    let predecessor = env::predecessor_account_id();
+    assert!(predecessor == self.note_address || predecessor == self.receipt_address, "Only note or receipt contract can call this function");
+    // tokens of closed loans are burned, there is nothing to update
+    let (lender_id, borrower_id) = match self.loan_owners.get(&token_id) {
+      Some(owners) => owners,
+      None => return
+    };
+
+    if predecessor == self.note_address {
+      remove_from_loan_index(&mut self.loans_by_lender, &lender_id, &token_id);
+      add_to_loan_index(&mut self.loans_by_lender, &new_owner_id, &token_id, "lender");
+      self.loan_owners.insert(&token_id, &(new_owner_id, borrower_id));
+    } else {
+      remove_from_loan_index(&mut self.loans_by_borrower, &borrower_id, &token_id);
+      add_to_loan_index(&mut self.loans_by_borrower, &new_owner_id, &token_id, "borrower");
+      self.loan_owners.insert(&token_id, &(lender_id, new_owner_id));
+    }
+  }
+
+  // loans whose note is held by the account
+  pub fn get_loans_by_lender(&self, account_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<LoanView> {
+    self.paginate_loans(self.loans_by_lender.get(&account_id), from_index, limit)
+  }
+
+  // loans whose receipt is held by the account
+  pub fn get_loans_by_borrower(&self, account_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<LoanView> {
+    self.paginate_loans(self.loans_by_borrower.get(&account_id), from_index, limit)
+  }
+
+  pub fn get_active_loans_for_collection(&self, nft_collection_id: NftCollection, from_index: Option<U128>, limit: Option<u64>) -> Vec<LoanView> {
+    self.paginate_loans(self.loans_by_collection.get(&nft_collection_id), from_index, limit)
+  }
+}
+
+impl LendingNftCollateral {
+
+  // the loan is listed under its note and receipt holders from the moment the tokens are minted
+  pub fn index_loan_owners(&mut self, token_id: &TokenId, lender_id: &AccountId, borrower_id: &AccountId) {
+    add_to_loan_index(&mut self.loans_by_lender, lender_id, token_id, "lender");
+    add_to_loan_index(&mut self.loans_by_borrower, borrower_id, token_id, "borrower");
+    self.loan_owners.insert(token_id, &(lender_id.clone(), borrower_id.clone()));
+  }
+
+  // keeps the indexes in line with the loan's status, the collection index only lists active
+  // loans and the owner indexes drop the loan once its tokens are burned
+  pub fn update_loan_indexes(&mut self, token_id: &TokenId, loan: &Loan) {
+    if loan.status == LoanStatus::Active {
+      add_to_loan_index(&mut self.loans_by_collection, &loan.warranty_collection, token_id, "collection");
+    } else {
+      remove_from_loan_index(&mut self.loans_by_collection, &loan.warranty_collection, token_id);
+    }
+
+    if loan.status != LoanStatus::Pending && loan.status != LoanStatus::Active {
+      if let Some((lender_id, borrower_id)) = self.loan_owners.remove(token_id) {
+        remove_from_loan_index(&mut self.loans_by_lender, &lender_id, token_id);
+        remove_from_loan_index(&mut self.loans_by_borrower, &borrower_id, token_id);
+      }
+    }
+  }
+
+  pub fn paginate_loans(&self, token_ids: Option<UnorderedSet<TokenId>>, from_index: Option<U128>, limit: Option<u64>) -> Vec<LoanView> {
+    let token_ids = match token_ids {
+      Some(token_ids) => token_ids,
+      None => return Vec::new()
+    };
+    let limit = limit.map(|limit| limit as usize).unwrap_or(usize::MAX);
+    assert!(limit != 0, "Cannot provide limit of 0");
+    let start_index = from_index.map(|index| index.0).unwrap_or(0);
+    token_ids.iter()
+      .skip(start_index as usize)
+      .take(limit)
+      .filter_map(|token_id| self.get_loan(token_id))
+      .collect()
+  }
+}
+
+// each account or collection gets its own set, prefixed by its id and the index name
+fn add_to_loan_index(index: &mut LookupMap<AccountId, UnorderedSet<TokenId>>, key: &AccountId, token_id: &TokenId, index_name: &str) {
+  let mut token_ids = index.get(key).unwrap_or_else(|| {
+    let mut set_id = key.clone();
+    set_id.push_str(index_name);
+    set_id.push_str("_loans");
+    UnorderedSet::new(set_id.into_bytes())
+  });
+  token_ids.insert(token_id);
+  index.insert(key, &token_ids);
+}
+
+fn remove_from_loan_index(index: &mut LookupMap<AccountId, UnorderedSet<TokenId>>, key: &AccountId, token_id: &TokenId) {
+  if let Some(mut token_ids) = index.get(key) {
+    token_ids.remove(token_id);
+    if token_ids.is_empty() {
+      index.remove(key);
+    } else {
+      index.insert(key, &token_ids);
+    }
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::MockedBlockchain;
+
+  use super::*;
+  use crate::loan::tests::sample_loan;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+
+  fn insert_loan(contract: &mut LendingNftCollateral, token_id: &str, status: LoanStatus) {
+    let token_id = token_id.to_string();
+    contract.loans.insert(&token_id, &Loan{status, ..sample_loan(1000, 0)});
+    contract.index_loan_owners(&token_id, &accounts(4).into(), &accounts(5).into());
+  }
+
+  #[test]
+  fn test_loan_indexes_follow_status() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    insert_loan(&mut contract, "0", LoanStatus::Pending);
+    insert_loan(&mut contract, "1", LoanStatus::Pending);
+    let nft_collection_id = "nft_collection_test".to_string();
+    assert_eq!(contract.get_loans_by_lender(accounts(4).into(), None, None).len(), 2);
+    assert!(contract.get_active_loans_for_collection(nft_collection_id.clone(), None, None).is_empty());
+
+    let mut loan = contract.loans.get(&"0".to_string()).unwrap();
+    contract.set_loan_status(&"0".to_string(), &mut loan, LoanStatus::Active);
+    let active_loans = contract.get_active_loans_for_collection(nft_collection_id.clone(), None, None);
+    assert_eq!(active_loans.len(), 1);
+    assert_eq!(active_loans[0].token_id, "0".to_string());
+
+    contract.set_loan_status(&"0".to_string(), &mut loan, LoanStatus::Repaid);
+    assert!(contract.get_active_loans_for_collection(nft_collection_id, None, None).is_empty());
+    let borrower_loans = contract.get_loans_by_borrower(accounts(5).into(), None, None);
+    assert_eq!(borrower_loans.len(), 1);
+    assert_eq!(borrower_loans[0].token_id, "1".to_string());
+  }
+
+  #[test]
+  fn test_get_loans_by_borrower_paginated() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    for token_id in ["0", "1", "2"].iter() {
+      insert_loan(&mut contract, token_id, LoanStatus::Active);
+    }
+    let first_page = contract.get_loans_by_borrower(accounts(5).into(), None, Some(2));
+    assert_eq!(first_page.len(), 2);
+    let second_page = contract.get_loans_by_borrower(accounts(5).into(), Some(U128(2)), Some(2));
+    assert_eq!(second_page.len(), 1);
+    assert_eq!(second_page[0].token_id, "2".to_string());
+    assert!(contract.get_loans_by_borrower(accounts(1).into(), None, None).is_empty());
+  }
+
+  #[test]
+  fn test_update_loan_token_owner() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    insert_loan(&mut contract, "0", LoanStatus::Active);
+    // the note is sold by the lender
+    testing_env!(context
+      .predecessor_account_id(accounts(2))
+      .build());
+    contract.update_loan_token_owner("0".to_string(), accounts(1).into());
+    assert!(contract.get_loans_by_lender(accounts(4).into(), None, None).is_empty());
+    assert_eq!(contract.get_loans_by_lender(accounts(1).into(), None, None).len(), 1);
+    assert_eq!(contract.get_loans_by_borrower(accounts(5).into(), None, None).len(), 1);
+  }
+
+  #[test]
+  #[should_panic(expected = "Only note or receipt contract can call this function")]
+  fn test_update_loan_token_owner_not_token_contract() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    insert_loan(&mut contract, "0", LoanStatus::Active);
+    testing_env!(context
+      .predecessor_account_id(accounts(4))
+      .build());
+    contract.update_loan_token_owner("0".to_string(), accounts(4).into());
+  }
+}
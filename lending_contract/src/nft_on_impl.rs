@@ -23,9 +23,30 @@ impl LendingNftCollateral {
         let parsed_message: Value = serde_json::from_str(&msg).unwrap();
 
         if parsed_message["function"].as_str().unwrap() == "post_borrowing_offer" {
-            self.post_borrowing_offer(env::predecessor_account_id(), U128(parsed_message["args"]["value_offered"].as_str().unwrap().parse().unwrap()), token_id, previous_owner_id);
-        } else if parsed_message["function"].as_str().unwrap() == "pay_loan" {
-            self.pay_loan(token_id, previous_owner_id);
+            let nft_collection_id = env::predecessor_account_id();
+            let borrowing_offer = Offer {
+                owner_id: previous_owner_id,
+                value: parsed_message["args"]["value_offered"].as_str().unwrap().parse().unwrap(),
+                token_id: Some(token_id.clone()),
+                apr: parsed_message["args"]["apr"].as_str().unwrap().parse().unwrap(),
+                loan_duration: parsed_message["args"]["loan_duration"].as_str().unwrap().parse().unwrap(),
+                currency: parsed_message["args"]["currency"].as_str().map(|currency| currency.to_string()),
+                expires_at: parsed_message["args"]["expires_at"].as_str().map(|expires_at| expires_at.parse().unwrap()),
+                ..Default::default()
+            };
+            self.assert_valid_borrowing_offer(&nft_collection_id, &borrowing_offer);
+            self.commit_collateral(&borrowing_offer.collateral(&nft_collection_id));
+            // the offer is posted once the collateral's traits are known, so lending offers targeting them can match
+            ext_nft_contract::nft_token(token_id, &nft_collection_id, NO_DEPOSIT, BASE_GAS)
+                .then(ext_self::resolve_collateral_traits(
+                    nft_collection_id.clone(),
+                    borrowing_offer,
+                    &env::current_account_id(),
+                    NO_DEPOSIT,
+                    TRAITS_CALLBACK_GAS
+                ));
+        } else if parsed_message["function"].as_str().unwrap() == "add_to_bundle" {
+            self.add_to_pending_bundle(env::predecessor_account_id(), token_id, previous_owner_id);
         } else if parsed_message["function"].as_str().unwrap() == "transfer_warranty" {
             //needs to find a way to receive money
             self.transfer_warranty_loan(token_id, sender_id);
@@ -0,0 +1,157 @@
+use crate::*;
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // offers resting in any book that the account owns
+  pub fn get_offers_by_account(&self, account_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<Offer> {
+    let offer_keys = match self.offers_by_account.get(&account_id) {
+      Some(offer_keys) => offer_keys,
+      None => return Vec::new()
+    };
+    let limit = limit.map(|limit| limit as usize).unwrap_or(usize::MAX);
+    assert!(limit != 0, "Cannot provide limit of 0");
+    let start_index = from_index.map(|index| index.0).unwrap_or(0);
+    offer_keys.iter()
+      .skip(start_index as usize)
+      .take(limit)
+      .filter_map(|(nft_collection_id, side, offer_id)| match side {
+        OfferSide::Lending => self.get_lending_offers_map(&nft_collection_id).get(&offer_id),
+        OfferSide::Borrowing => self.get_borrowing_offers_map(&nft_collection_id).get(&offer_id)
+      })
+      .collect()
+  }
+
+  // removes up to limit of the caller's offers, in a single collection or in all of them. Escrowed
+  // funds are refunded with one transfer per currency and the NFTs go back in one batch, returns
+  // how many offers were cancelled
+  pub fn cancel_all_offers(&mut self, nft_collection_id: Option<NftCollection>, limit: u64) -> u64 {
+    assert!(limit != 0, "Cannot provide limit of 0");
+    let owner_id = env::predecessor_account_id();
+    let offer_keys: Vec<(NftCollection, OfferSide, String)> = match self.offers_by_account.get(&owner_id) {
+      Some(offer_keys) => offer_keys.iter()
+        .filter(|(offer_collection_id, _, _)| nft_collection_id.is_none() || nft_collection_id.as_ref() == Some(offer_collection_id))
+        .take(limit as usize)
+        .collect(),
+      None => Vec::new()
+    };
+
+    let mut refunds: Vec<(Option<AccountId>, u128)> = Vec::new();
+    let mut collateral = Vec::new();
+    for (offer_collection_id, side, offer_id) in offer_keys.iter() {
+      match side {
+        OfferSide::Lending => {
+          let lending_offer = self.remove_lending_offer(offer_collection_id.clone(), offer_id.clone());
+          match refunds.iter_mut().find(|(currency, _)| *currency == lending_offer.currency) {
+            Some((_, value)) => *value += lending_offer.value,
+            None => refunds.push((lending_offer.currency, lending_offer.value))
+          }
+        },
+        OfferSide::Borrowing => {
+          let borrowing_offer = self.remove_borrowing_offer(offer_collection_id.clone(), offer_id.clone());
+          collateral.extend(borrowing_offer.collateral(offer_collection_id));
+        }
+      }
+    }
+
+    for (currency, value) in refunds {
+      self.pay_out(owner_id.clone(), &currency, value);
+    }
+    if !collateral.is_empty() {
+      self.transfer_collateral(owner_id, collateral);
+    }
+    offer_keys.len() as u64
+  }
+}
+
+impl LendingNftCollateral {
+
+  pub fn index_offer(&mut self, owner_id: &AccountId, offer_key: (NftCollection, OfferSide, String)) {
+    let mut offer_keys = self.offers_by_account.get(owner_id).unwrap_or_else(|| {
+      let mut set_id = owner_id.clone();
+      set_id.push_str("_offers");
+      UnorderedSet::new(set_id.into_bytes())
+    });
+    offer_keys.insert(&offer_key);
+    self.offers_by_account.insert(owner_id, &offer_keys);
+  }
+
+  pub fn unindex_offer(&mut self, owner_id: &AccountId, offer_key: &(NftCollection, OfferSide, String)) {
+    if let Some(mut offer_keys) = self.offers_by_account.get(owner_id) {
+      offer_keys.remove(offer_key);
+      if offer_keys.is_empty() {
+        self.offers_by_account.remove(owner_id);
+      } else {
+        self.offers_by_account.insert(owner_id, &offer_keys);
+      }
+    }
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::MockedBlockchain;
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  fn sample_offer(offer_id: &str, owner_id: ValidAccountId, value: u128) -> Offer {
+    Offer{offer_id: offer_id.to_string(), owner_id: owner_id.into(), value, token_id: Some(offer_id.to_string()), ..Default::default()}
+  }
+
+  #[test]
+  fn test_offers_by_account_follow_the_books() {
+    let context = get_context(accounts(4));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_offer("0", accounts(4), 30));
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_offer("0", accounts(4), 10));
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_offer("1", accounts(5), 10));
+    assert_eq!(contract.get_offers_by_account(accounts(4).into(), None, None).len(), 2);
+
+    // a partial draw keeps the offer listed, using it up removes it
+    contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 20);
+    assert_eq!(contract.get_offers_by_account(accounts(4).into(), None, None).len(), 2);
+    contract.draw_lending_offer(nft_collection_id.clone(), "0".to_string(), 10);
+    let offers = contract.get_offers_by_account(accounts(4).into(), None, None);
+    assert_eq!(offers.len(), 1);
+    assert_eq!(offers[0].value, 10);
+    assert_eq!(contract.get_offers_by_account(accounts(5).into(), None, Some(1)).len(), 1);
+  }
+
+  #[test]
+  fn test_cancel_all_offers() {
+    let context = get_context(accounts(4));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    for nft_collection_id in ["nft_collection_test1", "nft_collection_test2"].iter() {
+      contract.insert_lending_offer(nft_collection_id.to_string(), sample_offer("0", accounts(4), 30));
+      contract.insert_borrowing_offer(nft_collection_id.to_string(), sample_offer("1", accounts(4), 10));
+    }
+    contract.insert_lending_offer("nft_collection_test1".to_string(), sample_offer("2", accounts(5), 20));
+
+    assert_eq!(contract.cancel_all_offers(Some("nft_collection_test1".to_string()), 10), 2);
+    let remaining_offers = contract.get_offers_by_account(accounts(4).into(), None, None);
+    assert_eq!(remaining_offers.len(), 2);
+    assert_eq!(contract.get_best_lending_offer("nft_collection_test1".to_string()).unwrap().offer_id, "2".to_string());
+
+    assert_eq!(contract.cancel_all_offers(None, 1), 1);
+    assert_eq!(contract.cancel_all_offers(None, 10), 1);
+    assert!(contract.get_offers_by_account(accounts(4).into(), None, None).is_empty());
+    assert!(contract.get_best_borrowing_offer("nft_collection_test2".to_string()).is_none());
+    assert_eq!(contract.get_offers_by_account(accounts(5).into(), None, None).len(), 1);
+  }
+}
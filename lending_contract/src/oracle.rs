@@ -0,0 +1,191 @@
+use crate::*;
+
+// a collection's floor price as reported by the oracle, timestamp in nanoseconds
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FloorPrice {
+  pub price: U128,
+  pub timestamp: U128
+}
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  pub fn set_oracle(&mut self, oracle_id: AccountId, floor_price_max_age: U128) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    self.oracle_id = Some(oracle_id);
+    self.floor_price_max_age = floor_price_max_age.0;
+  }
+
+  // anyone can refresh a floor price, the oracle is the only source
+  pub fn update_floor_price(&mut self, nft_collection_id: NftCollection, currency: Option<AccountId>) -> Promise {
+    let oracle_id = self.oracle_id.clone().expect("There is no oracle set");
+    ext_oracle::get_floor_price(
+      nft_collection_id.clone(),
+      currency.clone(),
+      &oracle_id,
+      NO_DEPOSIT,
+      BASE_GAS
+    ).then(ext_self::resolve_floor_price(
+      nft_collection_id,
+      currency,
+      &env::current_account_id(),
+      NO_DEPOSIT,
+      BASE_GAS
+    ))
+  }
+
+  // prices from the future or older than the stored one are ignored
+  #[private]
+  pub fn resolve_floor_price(&mut self, nft_collection_id: NftCollection, currency: Option<AccountId>) -> bool {
+    let floor_price = match env::promise_result(0) {
+      PromiseResult::Successful(value) => match serde_json::from_slice::<Option<FloorPrice>>(&value) {
+        Ok(Some(floor_price)) => floor_price,
+        _ => return false
+      },
+      _ => return false
+    };
+    if floor_price.timestamp.0 > env::block_timestamp() as u128 {
+      return false;
+    }
+    let key = (nft_collection_id, currency);
+    if let Some(stored_floor_price) = self.floor_prices.get(&key) {
+      if stored_floor_price.timestamp.0 >= floor_price.timestamp.0 {
+        return false;
+      }
+    }
+    self.floor_prices.insert(&key, &floor_price);
+    true
+  }
+
+  pub fn get_floor_price(&self, nft_collection_id: NftCollection, currency: Option<AccountId>) -> Option<FloorPrice> {
+    self.floor_prices.get(&(nft_collection_id, currency))
+  }
+}
+
+impl LendingNftCollateral {
+
+  // the most that can be lent against the offer's collateral in its currency. Collections without
+  // a maximum loan-to-value aren't capped, a missing or stale floor price for any piece of the
+  // collateral doesn't allow any loan
+  pub fn get_ltv_loan_limit(&self, nft_collection_id: &NftCollection, borrowing_offer: &Offer) -> u128 {
+    let max_ltv = match self.collection_params.get(nft_collection_id).and_then(|params| params.max_ltv) {
+      Some(max_ltv) => max_ltv,
+      None => return u128::MAX
+    };
+    let current_time = env::block_timestamp() as u128;
+    let max_age = self.floor_price_max_age * NANOSECONDS_PER_SECOND;
+    let mut collateral_value: u128 = 0;
+    for collateral in borrowing_offer.collateral(nft_collection_id) {
+      match self.floor_prices.get(&(collateral.nft_collection_id, borrowing_offer.currency.clone())) {
+        Some(floor_price) if current_time.saturating_sub(floor_price.timestamp.0) <= max_age => {
+          collateral_value += floor_price.price.0;
+        },
+        _ => return 0
+      }
+    }
+    collateral_value * max_ltv / BASIS_POINTS
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::{MockedBlockchain, RuntimeFeesConfig, VMConfig};
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  fn setup_contract() -> LendingNftCollateral {
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+    let collection_params = CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 1000, max_apr: 10000, max_ltv: Some(5000), enabled: true, ..Default::default()};
+    contract.collection_params.insert(&"nft_collection_test".to_string(), &collection_params);
+    contract.floor_price_max_age = 3600;
+    contract
+  }
+
+  fn sample_borrowing_offer(value: u128) -> Offer {
+    Offer{offer_id: "0".to_string(), owner_id: accounts(5).into(), value, token_id: Some("token_id".to_string()), apr: 1000, loan_duration: 604800, ..Default::default()}
+  }
+
+  fn oracle_result(floor_price: Option<FloorPrice>) -> Vec<PromiseResult> {
+    vec![PromiseResult::Successful(serde_json::to_vec(&floor_price).unwrap())]
+  }
+
+  #[test]
+  fn test_resolve_floor_price() {
+    let mut context = get_context(accounts(0));
+    context.block_timestamp(1000);
+    testing_env!(context.build(), VMConfig::default(), RuntimeFeesConfig::default(), Default::default(), oracle_result(Some(FloorPrice{price: U128(100), timestamp: U128(900)})));
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    assert!(contract.resolve_floor_price(nft_collection_id.clone(), None));
+    assert_eq!(contract.get_floor_price(nft_collection_id.clone(), None).unwrap().price, U128(100));
+
+    // an older report doesn't replace the stored price
+    testing_env!(context.build(), VMConfig::default(), RuntimeFeesConfig::default(), Default::default(), oracle_result(Some(FloorPrice{price: U128(50), timestamp: U128(800)})));
+    assert!(!contract.resolve_floor_price(nft_collection_id.clone(), None));
+    testing_env!(context.build(), VMConfig::default(), RuntimeFeesConfig::default(), Default::default(), oracle_result(None));
+    assert!(!contract.resolve_floor_price(nft_collection_id.clone(), None));
+    assert_eq!(contract.get_floor_price(nft_collection_id, None).unwrap().price, U128(100));
+  }
+
+  #[test]
+  fn test_get_ltv_loan_limit() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    assert_eq!(contract.get_ltv_loan_limit(&nft_collection_id, &sample_borrowing_offer(10)), 0);
+    contract.floor_prices.insert(&(nft_collection_id.clone(), None), &FloorPrice{price: U128(100), timestamp: U128(0)});
+    assert_eq!(contract.get_ltv_loan_limit(&nft_collection_id, &sample_borrowing_offer(10)), 50);
+
+    // the price goes stale after the maximum age
+    testing_env!(context
+      .block_timestamp((3601 * NANOSECONDS_PER_SECOND) as u64)
+      .build());
+    assert_eq!(contract.get_ltv_loan_limit(&nft_collection_id, &sample_borrowing_offer(10)), 0);
+    assert_eq!(contract.get_ltv_loan_limit(&"uncapped_collection".to_string(), &sample_borrowing_offer(10)), u128::MAX);
+  }
+
+  #[test]
+  fn test_borrowing_offer_above_ltv_rests() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.floor_prices.insert(&(nft_collection_id.clone(), None), &FloorPrice{price: U128(100), timestamp: U128(0)});
+    let lending_offer = Offer{offer_id: "0".to_string(), owner_id: accounts(4).into(), value: 100, apr: 500, loan_duration: 604800, ..Default::default()};
+    contract.insert_lending_offer(nft_collection_id.clone(), lending_offer.clone());
+    assert!(contract.match_borrowing_offer(&nft_collection_id, &sample_borrowing_offer(60)).is_none());
+    assert!(contract.match_borrowing_offer(&nft_collection_id, &sample_borrowing_offer(50)).is_some());
+
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_borrowing_offer(60));
+    assert!(contract.match_lending_offer(&nft_collection_id, &lending_offer).is_empty());
+  }
+
+  #[test]
+  #[should_panic(expected = "The loan value is above the collection's maximum loan-to-value")]
+  fn test_post_loan_above_ltv() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = setup_contract();
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.floor_prices.insert(&(nft_collection_id.clone(), None), &FloorPrice{price: U128(100), timestamp: U128(0)});
+    let lending_offer = Offer{offer_id: "0".to_string(), owner_id: accounts(4).into(), value: 60, ..Default::default()};
+    contract.post_loan(nft_collection_id, lending_offer, sample_borrowing_offer(60), false);
+  }
+}
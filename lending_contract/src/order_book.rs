@@ -0,0 +1,246 @@
+use crate::*;
+
+// offers with the same value grouped together, total_value is the liquidity at that level
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceLevel {
+  pub value: U128,
+  pub count: u64,
+  pub total_value: U128
+}
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // lending offers from the highest to the lowest value
+  pub fn get_loan_offers(&self, nft_collection_id: NftCollection, from_index: Option<U128>, limit: Option<u64>) -> Vec<Offer> {
+    let offer_map = self.get_lending_offers_map(&nft_collection_id);
+    let book = self.get_lending_offers_book(&nft_collection_id);
+    let offer_ids = book.iter().map(|(_, offer_id)| offer_id);
+    paginate_offers(offer_ids, from_index, limit).iter().filter_map(|offer_id| offer_map.get(offer_id)).collect()
+  }
+
+  // borrowing offers from the lowest to the highest value requested
+  pub fn get_borrow_offers(&self, nft_collection_id: NftCollection, from_index: Option<U128>, limit: Option<u64>) -> Vec<Offer> {
+    let offer_map = self.get_borrowing_offers_map(&nft_collection_id);
+    let book = self.get_borrowing_offers_book(&nft_collection_id);
+    let offer_ids = book.iter().map(|(_, offer_id)| offer_id);
+    paginate_offers(offer_ids, from_index, limit).iter().filter_map(|offer_id| offer_map.get(offer_id)).collect()
+  }
+
+  // pagination is over price levels, in the same order as the offers. The value is part of the
+  // book key so the offers themselves aren't read
+  pub fn get_loan_offers_depth(&self, nft_collection_id: NftCollection, from_index: Option<U128>, limit: Option<u64>) -> Vec<PriceLevel> {
+    let book = self.get_lending_offers_book(&nft_collection_id);
+    let values = book.iter().map(|((price_key, _), _)| u128::MAX - price_key);
+    paginate_offers(aggregate_depth(values).into_iter(), from_index, limit)
+  }
+
+  // targeted lending offers are kept in their own book since they only fund some of the
+  // collection's tokens, each offer carries its target
+  pub fn get_targeted_loan_offers(&self, nft_collection_id: NftCollection, from_index: Option<U128>, limit: Option<u64>) -> Vec<Offer> {
+    let offer_map = self.get_lending_offers_map(&nft_collection_id);
+    let book = self.get_targeted_lending_offers_book(&nft_collection_id);
+    let offer_ids = book.iter().map(|(_, offer_id)| offer_id);
+    paginate_offers(offer_ids, from_index, limit).iter().filter_map(|offer_id| offer_map.get(offer_id)).collect()
+  }
+
+  pub fn get_targeted_loan_offers_depth(&self, nft_collection_id: NftCollection, from_index: Option<U128>, limit: Option<u64>) -> Vec<PriceLevel> {
+    let book = self.get_targeted_lending_offers_book(&nft_collection_id);
+    let values = book.iter().map(|((price_key, _), _)| u128::MAX - price_key);
+    paginate_offers(aggregate_depth(values).into_iter(), from_index, limit)
+  }
+
+  pub fn get_borrow_offers_depth(&self, nft_collection_id: NftCollection, from_index: Option<U128>, limit: Option<u64>) -> Vec<PriceLevel> {
+    let book = self.get_borrowing_offers_book(&nft_collection_id);
+    let values = book.iter().map(|((price_key, _), _)| price_key);
+    paginate_offers(aggregate_depth(values).into_iter(), from_index, limit)
+  }
+
+  // permissionless cleanup of the collection's books, lending offers are pruned first. Escrowed
+  // funds and NFTs go back to the offer owners, returns how many offers were removed
+  pub fn prune_expired_offers(&mut self, nft_collection_id: NftCollection, limit: u64) -> u64 {
+    assert!(limit != 0, "Cannot provide limit of 0");
+    let offer_map = self.get_lending_offers_map(&nft_collection_id);
+    let mut expired_lending_offers = expired_offers(&self.get_lending_offers_book(&nft_collection_id), &offer_map, limit as usize);
+    let targeted_limit = limit as usize - expired_lending_offers.len();
+    expired_lending_offers.extend(expired_offers(&self.get_targeted_lending_offers_book(&nft_collection_id), &offer_map, targeted_limit));
+    for offer in expired_lending_offers.iter() {
+      self.remove_lending_offer(nft_collection_id.clone(), offer.offer_id.clone());
+      self.pay_out(offer.owner_id.clone(), &offer.currency, offer.value);
+    }
+
+    let remaining_limit = limit as usize - expired_lending_offers.len();
+    let expired_borrowing_offers = expired_offers(&self.get_borrowing_offers_book(&nft_collection_id), &self.get_borrowing_offers_map(&nft_collection_id), remaining_limit);
+    for offer in expired_borrowing_offers.iter() {
+      self.remove_borrowing_offer(nft_collection_id.clone(), offer.offer_id.clone());
+      self.transfer_collateral(offer.owner_id.clone(), offer.collateral(&nft_collection_id));
+    }
+    (expired_lending_offers.len() + expired_borrowing_offers.len()) as u64
+  }
+}
+
+fn expired_offers(book: &TreeMap<(u128, u64), String>, offer_map: &LookupMap<String, Offer>, limit: usize) -> Vec<Offer> {
+  book.iter()
+    .filter_map(|(_, offer_id)| offer_map.get(&offer_id))
+    .filter(|offer| offer.is_expired())
+    .take(limit)
+    .collect()
+}
+
+fn aggregate_depth(values: impl Iterator<Item = u128>) -> Vec<PriceLevel> {
+  let mut levels: Vec<PriceLevel> = Vec::new();
+  for value in values {
+    match levels.last_mut() {
+      Some(level) if level.value.0 == value => {
+        level.count += 1;
+        level.total_value = U128(level.total_value.0 + value);
+      },
+      _ => levels.push(PriceLevel{value: U128(value), count: 1, total_value: U128(value)})
+    }
+  }
+  levels
+}
+
+fn paginate_offers<T>(items: impl Iterator<Item = T>, from_index: Option<U128>, limit: Option<u64>) -> Vec<T> {
+  let limit = limit.map(|limit| limit as usize).unwrap_or(usize::MAX);
+  assert!(limit != 0, "Cannot provide limit of 0");
+  let start_index = from_index.map(|index| index.0).unwrap_or(0);
+  items
+    .skip(start_index as usize)
+    .take(limit)
+    .collect()
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::MockedBlockchain;
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  fn sample_offer(offer_id: &str, value: u128) -> Offer {
+    Offer{offer_id: offer_id.to_string(), owner_id: accounts(4).into(), value, apr: 1000, loan_duration: 604800, ..Default::default()}
+  }
+
+  fn sample_expiring_offer(offer_id: &str, value: u128, expires_at: u128) -> Offer {
+    Offer{token_id: Some(offer_id.to_string()), expires_at: Some(expires_at), ..sample_offer(offer_id, value)}
+  }
+
+  #[test]
+  fn test_get_loan_offers() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    for (offer_id, value) in [("0", 10), ("1", 30), ("2", 20)].iter() {
+      contract.insert_lending_offer(nft_collection_id.clone(), sample_offer(offer_id, *value));
+    }
+    let offers = contract.get_loan_offers(nft_collection_id.clone(), None, None);
+    let values: Vec<u128> = offers.iter().map(|offer| offer.value).collect();
+    assert_eq!(values, vec![30, 20, 10]);
+    let page = contract.get_loan_offers(nft_collection_id.clone(), Some(U128(1)), Some(1));
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].offer_id, "2".to_string());
+    assert!(contract.get_loan_offers("unknown_collection".to_string(), None, None).is_empty());
+  }
+
+  #[test]
+  fn test_get_targeted_loan_offers() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_offer("0", 10));
+    for (offer_id, value) in [("1", 20), ("2", 30), ("3", 20)].iter() {
+      let target = OfferTarget::TokenIds(vec!["0".to_string()]);
+      contract.insert_lending_offer(nft_collection_id.clone(), Offer{target: Some(target), ..sample_offer(offer_id, *value)});
+    }
+
+    let offers = contract.get_targeted_loan_offers(nft_collection_id.clone(), None, None);
+    let values: Vec<u128> = offers.iter().map(|offer| offer.value).collect();
+    assert_eq!(values, vec![30, 20, 20]);
+    assert!(offers.iter().all(|offer| offer.target.is_some()));
+    let depth = contract.get_targeted_loan_offers_depth(nft_collection_id.clone(), None, None);
+    assert_eq!(depth, vec![
+      PriceLevel{value: U128(30), count: 1, total_value: U128(30)},
+      PriceLevel{value: U128(20), count: 2, total_value: U128(40)}
+    ]);
+    assert_eq!(contract.get_loan_offers(nft_collection_id, None, None).len(), 1);
+  }
+
+  #[test]
+  fn test_get_borrow_offers_depth() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    for (offer_id, value) in [("0", 20), ("1", 10), ("2", 10)].iter() {
+      contract.insert_borrowing_offer(nft_collection_id.clone(), sample_offer(offer_id, *value));
+    }
+
+    let depth = contract.get_borrow_offers_depth(nft_collection_id.clone(), None, None);
+    assert_eq!(depth, vec![
+      PriceLevel{value: U128(10), count: 2, total_value: U128(20)},
+      PriceLevel{value: U128(20), count: 1, total_value: U128(20)}
+    ]);
+    let offers = contract.get_borrow_offers(nft_collection_id, None, None);
+    assert_eq!(offers[0].offer_id, "1".to_string());
+    assert_eq!(offers[2].value, 20);
+  }
+
+  #[test]
+  #[should_panic(expected = "Cannot provide limit of 0")]
+  fn test_get_loan_offers_zero_limit() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.get_loan_offers("nft_collection_test".to_string(), None, Some(0));
+  }
+
+  #[test]
+  fn test_prune_expired_offers() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_expiring_offer("0", 10, 100));
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_expiring_offer("1", 20, 300));
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_offer("2", 30));
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_expiring_offer("0", 10, 200));
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_expiring_offer("1", 20, 400));
+
+    testing_env!(context
+      .block_timestamp(250)
+      .predecessor_account_id(accounts(5))
+      .build());
+    // expired offers are no longer matched or shown as the best offer
+    assert_eq!(contract.get_best_borrowing_offer(nft_collection_id.clone()).unwrap().offer_id, "1".to_string());
+    assert!(contract.match_lending_offer(&nft_collection_id, &sample_offer("3", 30)).iter().all(|offer| offer.offer_id == "1"));
+
+    assert_eq!(contract.prune_expired_offers(nft_collection_id.clone(), 1), 1);
+    assert!(contract.get_lending_offers_map(&nft_collection_id).get(&"0".to_string()).is_none());
+    assert_eq!(contract.prune_expired_offers(nft_collection_id.clone(), 10), 1);
+    assert!(contract.get_borrowing_offers_map(&nft_collection_id).get(&"0".to_string()).is_none());
+    assert_eq!(contract.prune_expired_offers(nft_collection_id.clone(), 10), 0);
+
+    let lending_offer_ids: Vec<String> = contract.get_loan_offers(nft_collection_id.clone(), None, None).iter().map(|offer| offer.offer_id.clone()).collect();
+    assert_eq!(lending_offer_ids, vec!["2".to_string(), "1".to_string()]);
+    assert_eq!(contract.get_borrow_offers(nft_collection_id, None, None).len(), 1);
+  }
+}
@@ -0,0 +1,354 @@
+use crate::*;
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // called by the receipt holder to pay the current loan off with a lending offer from the book,
+  // the collateral stays in the contract and backs the new loan. Any attached deposit is credited
+  // to the borrower's balance to cover a shortfall between the offer and the outstanding balance.
+  // The new loan takes as much of the offer as the collateral can back
+  #[payable]
+  pub fn refinance_loan(&mut self, token_id: TokenId, offer_id: String) -> Promise {
+    let loan = self.loans.get(&token_id).expect("Loan not found");
+    self.assert_active_loan(&loan);
+    assert!(self.get_grace_period_end(&loan) >= env::block_timestamp() as u128, "The grace period for this loan is over");
+    self.assert_collection_enabled(&loan.warranty_collection);
+    let borrower_id = env::predecessor_account_id();
+    if env::attached_deposit() > 0 {
+      self.credit_balance(borrower_id.clone(), env::attached_deposit());
+    }
+    let resting_offer = self.get_lending_offers_map(&loan.warranty_collection).get(&offer_id).expect("Offer not found");
+    assert!(resting_offer.accepts(&loan.collateral_offer()), "This offer doesn't lend against this NFT");
+    let loan_value = std::cmp::min(resting_offer.loan_value_cap(), self.calculate_refinance_loan_limit(&loan));
+    assert!(loan_value > 0, "The collateral can't back a new loan");
+    let lending_offer = self.draw_lending_offer(loan.warranty_collection.clone(), offer_id, loan_value);
+    assert!(lending_offer.currency == loan.currency, "The offer isn't denominated in the loan's currency");
+    let outstanding_balance = self.calculate_outstanding_balance(&loan);
+    let loan_proceeds = lending_offer.value - self.calculate_origination_fee(lending_offer.value);
+    assert!(
+      loan_proceeds + self.get_funds(borrower_id.clone(), &loan.currency) >= outstanding_balance,
+      "You don't have enough credit to refinance this loan"
+    );
+
+    ext_nft_contract::nft_token(
+      token_id.clone(),
+      &self.receipt_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    ).and(ext_nft_contract::nft_token(
+      token_id.clone(),
+      &self.note_address,
+      NO_DEPOSIT,
+      BASE_GAS
+    )).then(ext_self::resolve_refinance_owners(
+      token_id,
+      borrower_id,
+      lending_offer,
+      &env::current_account_id(),
+      NO_DEPOSIT,
+      REFINANCE_CALLBACK_GAS
+    ))
+  }
+
+  // mints the new note and receipt once the caller is confirmed as the receipt holder
+  #[private]
+  pub fn resolve_refinance_owners(&mut self, token_id: TokenId, borrower_id: AccountId, lending_offer: Offer) -> bool {
+    let receipt_owner_id = self.get_token_owner_from_promise(0);
+    let note_owner_id = self.get_token_owner_from_promise(1);
+    let loan = self.loans.get(&token_id).unwrap();
+
+    if receipt_owner_id != Some(borrower_id.clone()) || note_owner_id.is_none() || loan.status != LoanStatus::Active {
+      self.restore_lending_offer(loan.warranty_collection, lending_offer);
+      return false;
+    }
+
+    let new_loan = self.build_loan(&lending_offer, loan.warranty_collection.clone(), loan.warranty_token_id.clone(), loan.bundled_collateral.clone(), lending_offer.value);
+    let new_token_id = self.token_id_counter.to_string();
+    self.token_id_counter += 1;
+    self.mint_loan_tokens(new_token_id.clone(), &new_loan, lending_offer.owner_id.clone(), borrower_id.clone())
+      .then(ext_self::resolve_refinance_origination(
+        token_id,
+        new_token_id,
+        (note_owner_id.unwrap(), borrower_id),
+        lending_offer,
+        &env::current_account_id(),
+        NO_DEPOSIT,
+        CALLBACK_GAS
+      ));
+    true
+  }
+
+  // swaps the old loan for the new one once both tokens exist, otherwise the lending offer
+  // goes back to the book and the old loan stays untouched
+  #[private]
+  pub fn resolve_refinance_origination(&mut self, token_id: TokenId, new_token_id: TokenId, loan_owners: (AccountId, AccountId), lending_offer: Offer) -> bool {
+    let (note_owner_id, borrower_id) = loan_owners;
+    let note_minted = matches!(env::promise_result(0), PromiseResult::Successful(_));
+    let receipt_minted = matches!(env::promise_result(1), PromiseResult::Successful(_));
+    let mut loan = self.loans.get(&token_id).unwrap();
+    let outstanding_balance = self.calculate_outstanding_balance(&loan);
+    let borrower_balance = self.get_funds(borrower_id.clone(), &loan.currency);
+    // the new loan pays the origination fee like any other, out of what it lends
+    let loan_proceeds = lending_offer.value - self.calculate_origination_fee(lending_offer.value);
+    let shortfall = outstanding_balance.saturating_sub(loan_proceeds);
+
+    if !note_minted || !receipt_minted || shortfall > borrower_balance || loan.status != LoanStatus::Active {
+      if note_minted {
+        ext_nft_contract::nft_burn(new_token_id.clone(), &self.note_address, NO_DEPOSIT, BASE_GAS);
+      }
+      if receipt_minted {
+        ext_nft_contract::nft_burn(new_token_id, &self.receipt_address, NO_DEPOSIT, BASE_GAS);
+      }
+      self.restore_lending_offer(loan.warranty_collection, lending_offer);
+      return false;
+    }
+
+    // the new lender's escrow pays the old note holder, the borrower covers the difference
+    // or keeps whatever is left
+    self.set_funds(borrower_id.clone(), &loan.currency, borrower_balance - shortfall);
+    self.charge_origination_fee(&loan.currency, lending_offer.value);
+    if loan_proceeds > outstanding_balance {
+      self.pay_out(borrower_id.clone(), &loan.currency, loan_proceeds - outstanding_balance);
+    }
+    let interest_due = outstanding_balance.saturating_sub(loan.value.saturating_sub(loan.amount_repaid));
+    let fee = self.charge_interest_fee(&loan.currency, interest_due);
+    self.pay_out(note_owner_id, &loan.currency, outstanding_balance - fee);
+    self.burn_loan_tokens(token_id.clone());
+    self.set_loan_status(&token_id, &mut loan, LoanStatus::Repaid);
+    self.loan_extensions.remove(&token_id);
+
+    let mut new_loan = self.build_loan(&lending_offer, loan.warranty_collection, loan.warranty_token_id, loan.bundled_collateral, lending_offer.value);
+    new_loan.status = LoanStatus::Active;
+    self.loans.insert(&new_token_id, &new_loan);
+    self.index_loan_owners(&new_token_id, &lending_offer.owner_id, &borrower_id);
+    self.update_loan_indexes(&new_token_id, &new_loan);
+    true
+  }
+}
+
+impl LendingNftCollateral {
+
+  // the limits a borrowing offer on the same collateral would have to respect
+  pub fn calculate_refinance_loan_limit(&self, loan: &Loan) -> u128 {
+    let value_limit = if loan.bundled_collateral.is_empty() {
+      self.collection_params.get(&loan.warranty_collection).expect("This collection is not accepted as collateral").max_loan_value
+    } else {
+      self.calculate_bundle_loan_limit(&loan.collateral())
+    };
+    std::cmp::min(value_limit, self.get_ltv_loan_limit(&loan.warranty_collection, &loan.collateral_offer()))
+  }
+}
+
+impl Loan {
+
+  // the loan's collateral as a borrowing offer would hold it. Traits aren't stored with the loan,
+  // so offers targeting traits can't refinance it
+  pub fn collateral_offer(&self) -> Offer {
+    Offer{
+      token_id: Some(self.warranty_token_id.clone()),
+      bundled_collateral: self.bundled_collateral.clone(),
+      currency: self.currency.clone(),
+      ..Default::default()
+    }
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::{MockedBlockchain, RuntimeFeesConfig, VMConfig};
+
+  use super::*;
+  use crate::loan::tests::sample_loan;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+
+  fn sample_lending_offer(value: u128) -> Offer {
+    Offer{offer_id: "offer_id_test1".to_string(), owner_id: accounts(4).into(), value, token_id: None, apr: 1000, loan_duration: 604800, ..Default::default()}
+  }
+
+  #[test]
+  fn test_resolve_refinance_owners_not_receipt_owner() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let token = Token{token_id: "0".to_string(), owner_id: accounts(5).into(), metadata: None, approved_account_ids: None, royalty: None};
+    let token_result = serde_json::to_vec(&Some(token)).unwrap();
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Successful(token_result.clone()), PromiseResult::Successful(token_result)]
+    );
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    let success = contract.resolve_refinance_owners("0".to_string(), accounts(1).into(), sample_lending_offer(1200));
+    assert!(!success);
+    assert_eq!(contract.token_id_counter, 0);
+    // the lending offer is back in the book
+    let nft_collection_id = "nft_collection_test".to_string();
+    assert_eq!(contract.lending_offers.get(&nft_collection_id).unwrap().get(&"offer_id_test1".to_string()).unwrap().value, 1200);
+  }
+
+  #[test]
+  #[should_panic(expected = "The grace period for this loan is over")]
+  fn test_refinance_loan_after_grace_period() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{grace_period: 172800, ..Default::default()});
+    contract.insert_lending_offer(nft_collection_id, sample_lending_offer(1200));
+    contract.loans.insert(&"0".to_string(), &sample_loan(1000, 2000));
+    testing_env!(context
+      .block_timestamp((172801 * NANOSECONDS_PER_SECOND) as u64)
+      .predecessor_account_id(accounts(2))
+      .build());
+    contract.refinance_loan("0".to_string(), "offer_id_test1".to_string());
+  }
+
+  #[test]
+  fn test_refinance_loan_within_collateral_limits() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 1100, max_apr: 10000, enabled: true, ..Default::default()});
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer(1500));
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    contract.refinance_loan("0".to_string(), "offer_id_test1".to_string());
+    // the collection's maximum loan value is drawn, the rest of the offer stays in the book
+    assert_eq!(contract.get_lending_offers_map(&nft_collection_id).get(&"offer_id_test1".to_string()).unwrap().value, 400);
+
+    // a floor price of 2000 at 50% loan-to-value backs 1000
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{max_ltv: Some(5000), ..contract.collection_params.get(&nft_collection_id).unwrap()});
+    contract.floor_prices.insert(&(nft_collection_id.clone(), None), &FloorPrice{price: U128(2000), timestamp: U128(0)});
+    contract.insert_lending_offer(nft_collection_id.clone(), Offer{offer_id: "offer_id_test2".to_string(), ..sample_lending_offer(1500)});
+    contract.refinance_loan("0".to_string(), "offer_id_test2".to_string());
+    assert_eq!(contract.get_lending_offers_map(&nft_collection_id).get(&"offer_id_test2".to_string()).unwrap().value, 500);
+  }
+
+  #[test]
+  #[should_panic(expected = "This offer doesn't lend against this NFT")]
+  fn test_refinance_loan_outside_target() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 1100, max_apr: 10000, enabled: true, ..Default::default()});
+    let target = OfferTarget::TokenIds(vec!["other_token_id".to_string()]);
+    contract.insert_lending_offer(nft_collection_id, Offer{target: Some(target), ..sample_lending_offer(1200)});
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    contract.refinance_loan("0".to_string(), "offer_id_test1".to_string());
+  }
+
+  #[test]
+  #[should_panic(expected = "This collection is not accepting new offers")]
+  fn test_refinance_loan_disabled_collection() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 1100, max_apr: 10000, enabled: false, ..Default::default()});
+    contract.insert_lending_offer(nft_collection_id, sample_lending_offer(1200));
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    contract.refinance_loan("0".to_string(), "offer_id_test1".to_string());
+  }
+
+  #[test]
+  fn test_resolve_refinance_origination() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // half a year in the borrower owes 1100 and covers the 100 the new offer doesn't
+    testing_env!(
+      context
+        .block_timestamp((YEAR_IN_SECONDS / 2 * NANOSECONDS_PER_SECOND) as u64)
+        .predecessor_account_id(accounts(0))
+        .build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Successful(vec![]), PromiseResult::Successful(vec![])]
+    );
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    contract.balances.insert(&accounts(1).into(), &(150));
+    let success = contract.resolve_refinance_origination("0".to_string(), "1".to_string(), (accounts(2).into(), accounts(1).into()), sample_lending_offer(1000));
+    assert!(success);
+    assert_eq!(contract.loans.get(&"0".to_string()).unwrap().status, LoanStatus::Repaid);
+    let new_loan = contract.loans.get(&"1".to_string()).unwrap();
+    assert_eq!(new_loan.value, 1000);
+    assert_eq!(new_loan.apr, 1000);
+    assert_eq!(new_loan.warranty_token_id, "token_id".to_string());
+    assert_eq!(contract.balances.get(&accounts(1).into()).unwrap(), 50);
+    let borrower_loans = contract.get_loans_by_borrower(accounts(1).into(), None, None);
+    assert_eq!(borrower_loans.len(), 1);
+    assert_eq!(borrower_loans[0].token_id, "1".to_string());
+  }
+
+  #[test]
+  fn test_resolve_refinance_origination_fee() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // the 1% fee on the new loan adds 10 to what the borrower covers
+    testing_env!(
+      context
+        .block_timestamp((YEAR_IN_SECONDS / 2 * NANOSECONDS_PER_SECOND) as u64)
+        .predecessor_account_id(accounts(1))
+        .build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Successful(vec![]), PromiseResult::Successful(vec![])]
+    );
+    contract.set_protocol_fees(U128(100), U128(0));
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    contract.balances.insert(&accounts(1).into(), &(150));
+    let success = contract.resolve_refinance_origination("0".to_string(), "1".to_string(), (accounts(2).into(), accounts(1).into()), sample_lending_offer(1000));
+    assert!(success);
+    assert_eq!(contract.balances.get(&accounts(1).into()).unwrap(), 40);
+    assert_eq!(contract.get_treasury_balance(None).0, 10);
+  }
+
+  #[test]
+  fn test_resolve_refinance_origination_failed_mint() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(
+      context.predecessor_account_id(accounts(0)).build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Failed, PromiseResult::Successful(vec![])]
+    );
+    contract.loans.insert(&"0".to_string(), &Loan{expiration_time: YEAR_IN_SECONDS * NANOSECONDS_PER_SECOND, ..sample_loan(1000, 2000)});
+    let success = contract.resolve_refinance_origination("0".to_string(), "1".to_string(), (accounts(2).into(), accounts(1).into()), sample_lending_offer(1200));
+    assert!(!success);
+    assert!(contract.loans.get(&"0".to_string()).is_some());
+    assert!(contract.loans.get(&"1".to_string()).is_none());
+    let nft_collection_id = "nft_collection_test".to_string();
+    assert_eq!(contract.lending_offers.get(&nft_collection_id).unwrap().get(&"offer_id_test1".to_string()).unwrap().value, 1200);
+  }
+}
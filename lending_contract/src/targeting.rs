@@ -0,0 +1,271 @@
+use crate::*;
+
+// the tokens a targeted lending offer is willing to lend against
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OfferTarget {
+  // any one of these tokens
+  TokenIds(Vec<TokenId>),
+  // tokens that have every one of these traits
+  Traits(Vec<TokenTrait>)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenTrait {
+  pub trait_type: String,
+  pub value: String
+}
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // a lending offer that only funds loans against the targeted tokens, usually at a higher value
+  // than the collection-wide offers
+  #[payable]
+  pub fn post_targeted_lending_offer(&mut self, nft_collection_id: NftCollection, value_offered: U128, apr: U128, loan_duration: U128, target: OfferTarget, expires_at: Option<U128>) -> bool {
+    assert_valid_target(&target);
+    self.lock_funds(env::predecessor_account_id(), value_offered.0);
+    let lending_offer = Offer{owner_id: env::predecessor_account_id(), value: value_offered.0, apr: apr.0, loan_duration: loan_duration.0, expires_at: expires_at.map(|timestamp| timestamp.0), target: Some(target), ..Default::default()};
+    self.place_lending_offer(nft_collection_id, lending_offer)
+  }
+
+  // continues a borrowing offer posted through nft_on_transfer once the collection returns the
+  // collateral's metadata. A token without readable traits can still match by id. The collection
+  // may have changed since the NFT arrived, an offer that isn't valid anymore sends it back
+  #[private]
+  pub fn resolve_collateral_traits(&mut self, nft_collection_id: NftCollection, borrowing_offer: Offer) -> bool {
+    let traits = self.get_token_traits_from_promise(0);
+    let borrowing_offer = Offer{traits, ..borrowing_offer};
+    if !self.is_valid_borrowing_offer(&nft_collection_id, &borrowing_offer) {
+      self.transfer_collateral(borrowing_offer.owner_id.clone(), borrowing_offer.collateral(&nft_collection_id));
+      return false;
+    }
+    self.place_borrowing_offer(nft_collection_id, borrowing_offer)
+  }
+}
+
+impl LendingNftCollateral {
+
+  pub fn get_token_traits_from_promise(&self, result_index: u64) -> Vec<TokenTrait> {
+    match env::promise_result(result_index) {
+      PromiseResult::Successful(value) => match serde_json::from_slice::<Option<Token>>(&value) {
+        Ok(Some(token)) => token.metadata
+          .and_then(|metadata| metadata.extra)
+          .map(|extra| parse_token_traits(&extra))
+          .unwrap_or_default(),
+        _ => Vec::new()
+      },
+      _ => Vec::new()
+    }
+  }
+}
+
+impl Offer {
+
+  // collection-wide lending offers accept any collateral
+  pub fn accepts(&self, borrowing_offer: &Offer) -> bool {
+    match &self.target {
+      None => true,
+      Some(OfferTarget::TokenIds(token_ids)) => matches!(&borrowing_offer.token_id, Some(token_id) if token_ids.contains(token_id)),
+      Some(OfferTarget::Traits(traits)) => traits.iter().all(|token_trait| borrowing_offer.traits.contains(token_trait))
+    }
+  }
+}
+
+pub fn assert_valid_target(target: &OfferTarget) {
+  let is_empty = match target {
+    OfferTarget::TokenIds(token_ids) => token_ids.is_empty(),
+    OfferTarget::Traits(traits) => traits.is_empty()
+  };
+  assert!(!is_empty, "The target must list at least one token or trait");
+}
+
+// metadata extra is free-form, this reads the usual layouts: a list of {trait_type, value}
+// objects, the same list under "attributes", or an object of trait_type to value
+pub fn parse_token_traits(extra: &str) -> Vec<TokenTrait> {
+  let parsed_extra: Value = match serde_json::from_str(extra) {
+    Ok(parsed_extra) => parsed_extra,
+    Err(_) => return Vec::new()
+  };
+  let attributes = match parsed_extra.get("attributes") {
+    Some(attributes) => attributes,
+    None => &parsed_extra
+  };
+
+  match attributes {
+    Value::Array(attributes) => attributes.iter()
+      .filter_map(|attribute| Some(TokenTrait{
+        trait_type: attribute.get("trait_type")?.as_str()?.to_string(),
+        value: trait_value(attribute.get("value")?)?
+      }))
+      .collect(),
+    Value::Object(attributes) => attributes.iter()
+      .filter_map(|(trait_type, value)| Some(TokenTrait{trait_type: trait_type.clone(), value: trait_value(value)?}))
+      .collect(),
+    _ => Vec::new()
+  }
+}
+
+// numbers and booleans are compared by their text
+fn trait_value(value: &Value) -> Option<String> {
+  match value {
+    Value::String(value) => Some(value.clone()),
+    Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+    _ => None
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::{MockedBlockchain, RuntimeFeesConfig, VMConfig};
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  fn token_trait(trait_type: &str, value: &str) -> TokenTrait {
+    TokenTrait{trait_type: trait_type.to_string(), value: value.to_string()}
+  }
+
+  fn sample_borrowing_offer(token_id: &str, value: u128, traits: Vec<TokenTrait>) -> Offer {
+    Offer{offer_id: token_id.to_string(), owner_id: accounts(5).into(), value, token_id: Some(token_id.to_string()), apr: 1000, loan_duration: 604800, traits, ..Default::default()}
+  }
+
+  fn sample_lending_offer(offer_id: &str, value: u128, target: Option<OfferTarget>) -> Offer {
+    Offer{offer_id: offer_id.to_string(), owner_id: accounts(4).into(), value, apr: 500, loan_duration: 604800, target, ..Default::default()}
+  }
+
+  #[test]
+  fn test_parse_token_traits() {
+    let expected_traits = vec![token_trait("background", "gold"), token_trait("level", "3")];
+    assert_eq!(parse_token_traits(r#"{"attributes": [{"trait_type": "background", "value": "gold"}, {"trait_type": "level", "value": 3}]}"#), expected_traits);
+    assert_eq!(parse_token_traits(r#"[{"trait_type": "background", "value": "gold"}, {"trait_type": "level", "value": 3}]"#), expected_traits);
+    assert_eq!(parse_token_traits(r#"{"background": "gold", "level": 3}"#), expected_traits);
+    assert!(parse_token_traits("not json").is_empty());
+  }
+
+  #[test]
+  fn test_match_targeted_lending_offer_first() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("0", 50, None));
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("1", 20, Some(OfferTarget::TokenIds(vec!["rare".to_string()]))));
+    contract.insert_lending_offer(nft_collection_id.clone(), sample_lending_offer("2", 30, Some(OfferTarget::Traits(vec![token_trait("background", "gold")]))));
+
+    // targeted offers stay out of the collection-wide book
+    assert_eq!(contract.get_lending_offers_book(&nft_collection_id).len(), 1);
+    let matched_offer = contract.match_borrowing_offer(&nft_collection_id, &sample_borrowing_offer("rare", 10, Vec::new())).unwrap();
+    assert_eq!(matched_offer.offer_id, "1".to_string());
+    let matched_offer = contract.match_borrowing_offer(&nft_collection_id, &sample_borrowing_offer("other", 10, vec![token_trait("background", "gold")])).unwrap();
+    assert_eq!(matched_offer.offer_id, "2".to_string());
+    let matched_offer = contract.match_borrowing_offer(&nft_collection_id, &sample_borrowing_offer("other", 10, vec![token_trait("background", "blue")])).unwrap();
+    assert_eq!(matched_offer.offer_id, "0".to_string());
+
+    // a targeted offer drawn down or cancelled leaves its own book
+    contract.draw_lending_offer(nft_collection_id.clone(), "1".to_string(), 20);
+    assert!(contract.get_lending_offers_map(&nft_collection_id).get(&"1".to_string()).is_none());
+    assert_eq!(contract.get_targeted_lending_offers_book(&nft_collection_id).len(), 1);
+  }
+
+  #[test]
+  fn test_targeted_lending_offer_only_takes_eligible_borrowing_offers() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_borrowing_offer("0", 10, Vec::new()));
+    contract.insert_borrowing_offer(nft_collection_id.clone(), sample_borrowing_offer("1", 10, vec![token_trait("background", "gold")]));
+    let lending_offer = sample_lending_offer("0", 30, Some(OfferTarget::Traits(vec![token_trait("background", "gold")])));
+    let matches = contract.match_lending_offer(&nft_collection_id, &lending_offer);
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].offer_id, "1".to_string());
+  }
+
+  fn sample_token_metadata(extra: &str) -> TokenMetadata {
+    TokenMetadata {
+      title: None,
+      description: None,
+      media: None,
+      media_hash: None,
+      copies: None,
+      issued_at: None,
+      expires_at: None,
+      starts_at: None,
+      updated_at: None,
+      extra: Some(extra.to_string()),
+      reference: None,
+      reference_hash: None,
+      loan_value: None,
+      loan_expiration_time: None,
+      warranty_collection: None,
+      warranty_token_id: None
+    }
+  }
+
+  #[test]
+  fn test_resolve_collateral_traits() {
+    let context = get_context(accounts(0));
+    let metadata = sample_token_metadata(r#"{"background": "gold"}"#);
+    let token = Token{token_id: "0".to_string(), owner_id: accounts(0).into(), metadata: Some(metadata), approved_account_ids: None, royalty: None};
+    testing_env!(
+      context.build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Successful(serde_json::to_vec(&Some(token)).unwrap())]
+    );
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: true, ..Default::default()});
+    assert!(contract.resolve_collateral_traits(nft_collection_id.clone(), sample_borrowing_offer("0", 10, Vec::new())));
+    let borrowing_offer = contract.get_best_borrowing_offer(nft_collection_id).unwrap();
+    assert_eq!(borrowing_offer.traits, vec![token_trait("background", "gold")]);
+  }
+
+  #[test]
+  fn test_resolve_collateral_traits_returns_collateral() {
+    let context = get_context(accounts(0));
+    testing_env!(
+      context.build(),
+      VMConfig::default(),
+      RuntimeFeesConfig::default(),
+      Default::default(),
+      vec![PromiseResult::Failed]
+    );
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    // the collection stopped accepting offers while the traits were read
+    let nft_collection_id = "nft_collection_test".to_string();
+    contract.collection_params.insert(&nft_collection_id, &CollectionParams{min_loan_duration: 604800, max_loan_duration: 7776000, max_loan_value: 100, max_apr: 10000, enabled: false, ..Default::default()});
+    let borrowing_offer = sample_borrowing_offer("0", 10, Vec::new());
+    contract.commit_collateral(&borrowing_offer.collateral(&nft_collection_id));
+    assert!(!contract.resolve_collateral_traits(nft_collection_id.clone(), borrowing_offer));
+    assert!(contract.get_best_borrowing_offer(nft_collection_id.clone()).is_none());
+    assert!(!contract.committed_collateral.contains(&(nft_collection_id, "0".to_string())));
+  }
+
+  #[test]
+  #[should_panic(expected = "The target must list at least one token or trait")]
+  fn test_post_targeted_lending_offer_empty_target() {
+    let context = get_context(accounts(4));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.post_targeted_lending_offer("nft_collection_test".to_string(), U128(10), U128(500), U128(604800), OfferTarget::TokenIds(Vec::new()), None);
+  }
+}
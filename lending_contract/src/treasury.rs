@@ -0,0 +1,153 @@
+use crate::*;
+
+#[near_bindgen]
+impl LendingNftCollateral {
+
+  // both fees are in basis points, the origination fee over the loan value and the interest fee
+  // over the interest paid to note holders
+  pub fn set_protocol_fees(&mut self, origination_fee: U128, interest_fee: U128) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    assert!(origination_fee.0 <= BASIS_POINTS && interest_fee.0 <= BASIS_POINTS, "Fees can't be higher than 100%");
+    self.origination_fee = origination_fee.0;
+    self.interest_fee = interest_fee.0;
+  }
+
+  pub fn get_protocol_fees(&self) -> (U128, U128) {
+    (U128(self.origination_fee), U128(self.interest_fee))
+  }
+
+  pub fn get_treasury_balance(&self, currency: Option<AccountId>) -> U128 {
+    U128(self.treasury.get(&currency).unwrap_or(0))
+  }
+
+  // withdraws collected fees to the owner, user balances are kept apart and never touched
+  pub fn retrieve_funds(&mut self, currency: Option<AccountId>, value: U128) -> Promise {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    let treasury_balance = self.treasury.get(&currency).unwrap_or(0);
+    assert!(value.0 <= treasury_balance, "The treasury doesn't have enough funds");
+    self.treasury.insert(&currency, &(treasury_balance - value.0));
+
+    match currency {
+      Some(ft_contract_id) => ext_ft_contract::ft_transfer(
+        self.owner_id.clone(),
+        value,
+        None,
+        &ft_contract_id,
+        ONE_YOCTO,
+        FT_TRANSFER_GAS
+      ).then(ext_self::resolve_treasury_withdrawal(
+        ft_contract_id,
+        value,
+        &env::current_account_id(),
+        NO_DEPOSIT,
+        BASE_GAS
+      )),
+      None => Promise::new(self.owner_id.clone()).transfer(value.0)
+    }
+  }
+
+  // a failed withdrawal goes back to the treasury
+  #[private]
+  pub fn resolve_treasury_withdrawal(&mut self, ft_contract_id: AccountId, value: U128) -> bool {
+    if matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+      return true;
+    }
+    self.credit_treasury(&Some(ft_contract_id), value.0);
+    false
+  }
+}
+
+impl LendingNftCollateral {
+
+  pub fn credit_treasury(&mut self, currency: &Option<AccountId>, value: u128) {
+    let treasury_balance = self.treasury.get(currency).unwrap_or(0);
+    self.treasury.insert(currency, &(treasury_balance + value));
+  }
+
+  pub fn calculate_origination_fee(&self, loan_value: u128) -> u128 {
+    loan_value * self.origination_fee / BASIS_POINTS
+  }
+
+  // both return the fee taken, which the caller deducts from what it pays out
+  pub fn charge_origination_fee(&mut self, currency: &Option<AccountId>, loan_value: u128) -> u128 {
+    let fee = self.calculate_origination_fee(loan_value);
+    self.credit_treasury(currency, fee);
+    fee
+  }
+
+  pub fn charge_interest_fee(&mut self, currency: &Option<AccountId>, interest: u128) -> u128 {
+    let fee = interest * self.interest_fee / BASIS_POINTS;
+    self.credit_treasury(currency, fee);
+    fee
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::MockedBlockchain;
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  #[test]
+  fn test_charge_protocol_fees() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.set_protocol_fees(U128(100), U128(1000));
+    assert_eq!(contract.charge_origination_fee(&None, 1000), 10);
+    assert_eq!(contract.charge_interest_fee(&None, 200), 20);
+    assert_eq!(contract.charge_interest_fee(&Some(accounts(5).into()), 50), 5);
+    assert_eq!(contract.get_treasury_balance(None).0, 30);
+    assert_eq!(contract.get_treasury_balance(Some(accounts(5).into())).0, 5);
+  }
+
+  #[test]
+  fn test_retrieve_funds() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.credit_treasury(&None, 100);
+    contract.balances.insert(&accounts(1).into(), &(500));
+    contract.retrieve_funds(None, U128(60));
+    assert_eq!(contract.get_treasury_balance(None).0, 40);
+    assert_eq!(contract.balances.get(&accounts(1).into()).unwrap(), 500);
+  }
+
+  #[test]
+  #[should_panic(expected = "The treasury doesn't have enough funds")]
+  fn test_retrieve_funds_over_treasury() {
+    let context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    contract.credit_treasury(&None, 100);
+    contract.balances.insert(&accounts(1).into(), &(500));
+    contract.retrieve_funds(None, U128(101));
+  }
+
+  #[test]
+  #[should_panic(expected = "Only owner can call this function")]
+  fn test_set_protocol_fees_not_owner() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context.build());
+    let mut contract = LendingNftCollateral::new(accounts(1).into(), accounts(2).into(), accounts(3).into());
+
+    testing_env!(context
+      .predecessor_account_id(accounts(4))
+      .build());
+    contract.set_protocol_fees(U128(100), U128(1000));
+  }
+}
@@ -0,0 +1,91 @@
+// floor price oracle used to test the lending contract, the owner sets the prices it reports
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault};
+
+near_sdk::setup_alloc!();
+
+// same layout as the lending contract's FloorPrice, timestamp in nanoseconds
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FloorPrice {
+  pub price: U128,
+  pub timestamp: U128
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockOracle {
+  pub owner_id: AccountId,
+  pub floor_prices: LookupMap<(AccountId, Option<AccountId>), FloorPrice>
+}
+
+#[near_bindgen]
+impl MockOracle {
+
+  #[init]
+  pub fn new(owner_id: AccountId) -> Self {
+    assert!(!env::state_exists(), "Already initialized");
+    Self {
+      owner_id,
+      floor_prices: LookupMap::new(b"floor_prices".to_vec())
+    }
+  }
+
+  // a missing timestamp reports the price as of the current block
+  pub fn set_floor_price(&mut self, nft_collection_id: AccountId, currency: Option<AccountId>, price: U128, timestamp: Option<U128>) {
+    assert!(env::predecessor_account_id() == self.owner_id, "Only owner can call this function");
+    let timestamp = timestamp.unwrap_or(U128(env::block_timestamp() as u128));
+    self.floor_prices.insert(&(nft_collection_id, currency), &FloorPrice{price, timestamp});
+  }
+
+  pub fn get_floor_price(&self, nft_collection_id: AccountId, currency: Option<AccountId>) -> Option<FloorPrice> {
+    self.floor_prices.get(&(nft_collection_id, currency))
+  }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use near_sdk::json_types::ValidAccountId;
+  use near_sdk::test_utils::{accounts, VMContextBuilder};
+  use near_sdk::testing_env;
+  use near_sdk::MockedBlockchain;
+
+  use super::*;
+
+  fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+  }
+
+  #[test]
+  fn test_set_floor_price() {
+    let mut context = get_context(accounts(1));
+    testing_env!(context
+      .block_timestamp(1000)
+      .build());
+    let mut contract = MockOracle::new(accounts(1).into());
+
+    contract.set_floor_price("nft_collection_test".to_string(), None, U128(100), None);
+    contract.set_floor_price("nft_collection_test".to_string(), Some(accounts(2).into()), U128(50), Some(U128(500)));
+    assert_eq!(contract.get_floor_price("nft_collection_test".to_string(), None), Some(FloorPrice{price: U128(100), timestamp: U128(1000)}));
+    assert_eq!(contract.get_floor_price("nft_collection_test".to_string(), Some(accounts(2).into())), Some(FloorPrice{price: U128(50), timestamp: U128(500)}));
+    assert!(contract.get_floor_price("unknown_collection".to_string(), None).is_none());
+  }
+
+  #[test]
+  #[should_panic(expected = "Only owner can call this function")]
+  fn test_set_floor_price_not_owner() {
+    let context = get_context(accounts(2));
+    testing_env!(context.build());
+    let mut contract = MockOracle::new(accounts(1).into());
+
+    contract.set_floor_price("nft_collection_test".to_string(), None, U128(100), None);
+  }
+}
@@ -20,14 +20,28 @@ use near_contract_standards::non_fungible_token::metadata::{
 };
 use near_contract_standards::non_fungible_token::{Token, TokenId};
 use near_contract_standards::non_fungible_token::NonFungibleToken;
+use near_contract_standards::non_fungible_token::core::{NonFungibleTokenCore, NonFungibleTokenResolver};
+use std::collections::HashMap;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::LazyOption;
 use near_sdk::json_types::ValidAccountId;
 use near_sdk::{
-    env, near_bindgen, AccountId, BorshStorageKey, PanicOnDefault, Promise, PromiseOrValue,
+    env, ext_contract, near_bindgen, AccountId, Balance, BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseOrValue,
 };
 use near_contract_standards::non_fungible_token::events::{NftBurn};
 
+const NO_DEPOSIT: Balance = 0;
+const LENDING_CALL_GAS: Gas = 30_000_000_000_000;
+const LIQUIDATION_CALL_GAS: Gas = 100_000_000_000_000;
+
+#[ext_contract(ext_lending_contract)]
+trait LendingContract {
+    fn approve_loan_extension(&mut self, token_id: TokenId, note_owner_id: AccountId) -> Promise;
+    fn transfer_warranty_loan(&mut self, token_id: TokenId, sender_owner_id: AccountId) -> Promise;
+    fn liquidate_loan(&mut self, token_id: TokenId, note_owner_id: AccountId) -> Promise;
+    fn update_loan_token_owner(&mut self, token_id: TokenId, new_owner_id: AccountId);
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
@@ -44,6 +58,7 @@ enum StorageKey {
     TokenMetadata,
     Enumeration,
     Approval,
+    Royalties,
 }
 
 #[near_bindgen]
@@ -76,6 +91,7 @@ impl Contract {
                 Some(StorageKey::TokenMetadata),
                 Some(StorageKey::Enumeration),
                 Some(StorageKey::Approval),
+                Some(StorageKey::Royalties),
             ),
             metadata: LazyOption::new(StorageKey::Metadata, Some(&metadata)),
         }
@@ -93,17 +109,33 @@ impl Contract {
     pub fn nft_burn(
         &mut self,
         token_id: TokenId,
-        owner_id: ValidAccountId,
     ) -> bool{
         assert!(env::predecessor_account_id() == self.tokens.owner_id, "Only predecessor account id can burn");
-        self.tokens.owner_by_id.remove(&token_id);
-        self.tokens.token_metadata_by_id.remove(&token_id);
-        self.tokens.tokens_per_owner.get(&owner_id).remove(&token_id);
-        self.tokens.approvals_by_id.remove(&token_id);
-        self.tokens.next_approval_id_by_id.remove(&token_id);
-        self.tokens.royalties_by_id.remove(&token_id);
-
-        NftBurn { owner_id: &owner, token_ids: &[&token_id], memo: None, authorized_id: None }.emit();
+        let owner_id = self.tokens.owner_by_id.remove(&token_id).expect("Token not found");
+        if let Some(token_metadata_by_id) = &mut self.tokens.token_metadata_by_id {
+            token_metadata_by_id.remove(&token_id);
+        }
+        if let Some(tokens_per_owner) = &mut self.tokens.tokens_per_owner {
+            if let Some(mut owner_tokens) = tokens_per_owner.get(&owner_id) {
+                owner_tokens.remove(&token_id);
+                if owner_tokens.is_empty() {
+                    tokens_per_owner.remove(&owner_id);
+                } else {
+                    tokens_per_owner.insert(&owner_id, &owner_tokens);
+                }
+            }
+        }
+        if let Some(approvals_by_id) = &mut self.tokens.approvals_by_id {
+            approvals_by_id.remove(&token_id);
+        }
+        if let Some(next_approval_id_by_id) = &mut self.tokens.next_approval_id_by_id {
+            next_approval_id_by_id.remove(&token_id);
+        }
+        if let Some(royalties_by_id) = &mut self.tokens.royalties_by_id {
+            royalties_by_id.remove(&token_id);
+        }
+
+        NftBurn { owner_id: &owner_id, token_ids: &[&token_id], memo: None, authorized_id: None }.emit();
         true
     }
 
@@ -115,11 +147,145 @@ impl Contract {
         token_metadata: TokenMetadata,
     ) -> Token {
         assert!(env::predecessor_account_id() == self.tokens.owner_id, "Only predecessor account id can mint");
-        self.tokens.mint(token_id, receiver_id, Some(token_metadata))
+        self.tokens.internal_mint(token_id, receiver_id, Some(token_metadata), 0, HashMap::new())
+    }
+
+    /// Keep the loan terms stored in the token metadata in sync after the lending contract
+    /// changes them, e.g. when a loan is extended.
+    pub fn nft_update_loan_metadata(
+        &mut self,
+        token_id: TokenId,
+        loan_value: u128,
+        loan_expiration_time: u128,
+    ) {
+        assert!(env::predecessor_account_id() == self.tokens.owner_id, "Only predecessor account id can update loan metadata");
+        let token_metadata_by_id = self.tokens.token_metadata_by_id.as_mut().unwrap();
+        let mut token_metadata = token_metadata_by_id.get(&token_id).expect("Token not found");
+        token_metadata.loan_value = Some(loan_value);
+        token_metadata.loan_expiration_time = Some(loan_expiration_time);
+        token_metadata_by_id.insert(&token_id, &token_metadata);
+    }
+
+    /// Accept the extension the borrower proposed on the lending contract. Only the note
+    /// owner can approve it since they are the one receiving the settled interest.
+    pub fn approve_loan_extension(&mut self, token_id: TokenId) -> Promise {
+        let owner_id = self.tokens.owner_by_id.get(&token_id).expect("Token not found");
+        assert!(env::predecessor_account_id() == owner_id, "Only the token owner can approve a loan extension");
+        ext_lending_contract::approve_loan_extension(
+            token_id,
+            owner_id,
+            &self.tokens.owner_id,
+            NO_DEPOSIT,
+            LENDING_CALL_GAS,
+        )
+    }
+
+    /// Collect the collateral of a defaulted loan once its grace period is over. With
+    /// `liquidate` the collateral is sold in a descending-price auction on the lending
+    /// contract instead of being transferred to the note owner.
+    pub fn claim_collateral(&mut self, token_id: TokenId, liquidate: bool) -> Promise {
+        let owner_id = self.tokens.owner_by_id.get(&token_id).expect("Token not found");
+        assert!(env::predecessor_account_id() == owner_id, "Only the token owner can claim the collateral");
+        if liquidate {
+            ext_lending_contract::liquidate_loan(
+                token_id,
+                owner_id,
+                &self.tokens.owner_id,
+                NO_DEPOSIT,
+                LIQUIDATION_CALL_GAS,
+            )
+        } else {
+            ext_lending_contract::transfer_warranty_loan(
+                token_id,
+                owner_id,
+                &self.tokens.owner_id,
+                NO_DEPOSIT,
+                LENDING_CALL_GAS,
+            )
+        }
+    }
+}
+
+impl Contract {
+    /// Let the lending contract move the loan to the new holder in its lender and borrower
+    /// indexes. The same contract is deployed for notes and receipts, the lending contract
+    /// tells them apart by the caller.
+    fn notify_loan_token_owner(&self, token_id: TokenId, new_owner_id: AccountId) {
+        ext_lending_contract::update_loan_token_owner(
+            token_id,
+            new_owner_id,
+            &self.tokens.owner_id,
+            NO_DEPOSIT,
+            LENDING_CALL_GAS,
+        );
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenCore for Contract {
+    #[payable]
+    fn nft_transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) {
+        self.tokens.nft_transfer(receiver_id.clone(), token_id.clone(), approval_id, memo);
+        self.notify_loan_token_owner(token_id, receiver_id.into());
+    }
+
+    /// The lending contract is only notified from `nft_resolve_transfer`, once the receiver
+    /// has decided to keep the token.
+    #[payable]
+    fn nft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        self.tokens.nft_transfer_call(receiver_id, token_id, approval_id, memo, msg)
+    }
+
+    fn nft_token(self, token_id: TokenId) -> Option<Token> {
+        self.tokens.nft_token(token_id)
+    }
+
+    fn mint(
+        &mut self,
+        token_id: TokenId,
+        token_owner_id: ValidAccountId,
+        token_metadata: Option<TokenMetadata>,
+    ) -> Token {
+        self.nft_mint(token_id, token_owner_id, token_metadata.expect("Must provide metadata"))
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenResolver for Contract {
+    #[private]
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool {
+        let transferred = self.tokens.nft_resolve_transfer(
+            previous_owner_id,
+            receiver_id.clone(),
+            token_id.clone(),
+            approved_account_ids,
+        );
+        if transferred {
+            self.notify_loan_token_owner(token_id, receiver_id);
+        }
+        transferred
     }
 }
 
-near_contract_standards::impl_non_fungible_token_core!(Contract, tokens);
 near_contract_standards::impl_non_fungible_token_approval!(Contract, tokens);
 near_contract_standards::impl_non_fungible_token_enumeration!(Contract, tokens);
 
@@ -134,7 +300,8 @@ impl NonFungibleTokenMetadataProvider for Contract {
 mod tests {
     use near_sdk::test_utils::{accounts, VMContextBuilder};
     use near_sdk::testing_env;
-    use near_sdk::MockedBlockchain;
+    use near_sdk::serde_json::{self, Value};
+    use near_sdk::{MockedBlockchain, PromiseResult, RuntimeFeesConfig, VMConfig};
 
     use super::*;
 
@@ -163,7 +330,6 @@ mod tests {
             extra: None,
             reference: None,
             reference_hash: None,
-            loan_id: None,
             loan_value: None,
             loan_expiration_time: None,
             warranty_collection: None,
@@ -373,4 +539,76 @@ mod tests {
             .build());
         assert!(!contract.nft_is_approved(token_id.clone(), accounts(1), Some(1)));
     }
+
+    /// Method names of the function calls scheduled on `receiver_id` by the last call.
+    fn scheduled_calls(receiver_id: ValidAccountId) -> Vec<String> {
+        near_sdk::test_utils::get_created_receipts()
+            .iter()
+            // the receipt's deposit is a u128, which only serializes to a string
+            .map(|receipt| serde_json::from_str::<Value>(&serde_json::to_string(receipt).unwrap()).unwrap())
+            .filter(|receipt| receipt["receiver_id"] == receiver_id.to_string())
+            .flat_map(|receipt| receipt["actions"].as_array().unwrap().clone())
+            .filter_map(|action| action["FunctionCall"]["method_name"].as_str().map(|method_name| method_name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_transfer_notifies_lending_contract() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        let token_id = "0".to_string();
+        contract.nft_mint(token_id.clone(), accounts(1), sample_token_metadata());
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_transfer(accounts(2), token_id, None, None);
+        assert_eq!(scheduled_calls(accounts(0)), vec!["update_loan_token_owner".to_string()]);
+    }
+
+    #[test]
+    fn test_resolve_transfer_notifies_lending_contract() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        let token_id = "0".to_string();
+        contract.nft_mint(token_id.clone(), accounts(2), sample_token_metadata());
+
+        // the receiver kept the token
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"false".to_vec())]
+        );
+        assert!(contract.nft_resolve_transfer(accounts(1).into(), accounts(2).into(), token_id.clone(), None));
+        assert_eq!(scheduled_calls(accounts(0)), vec!["update_loan_token_owner".to_string()]);
+
+        // the receiver returned the token, the lending contract already has the right owner
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::default(),
+            RuntimeFeesConfig::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"true".to_vec())]
+        );
+        assert!(!contract.nft_resolve_transfer(accounts(1).into(), accounts(2).into(), token_id, None));
+        assert!(scheduled_calls(accounts(0)).is_empty());
+    }
 }